@@ -1,5 +1,4 @@
 use futures::future::join_all;
-use itertools::Itertools;
 use scylla::{
     batch::{Batch, BatchStatement},
     serialize::row::SerializeRow,
@@ -8,10 +7,13 @@ use scylla::{
     QueryResult, Session,
 };
 
+/// Runs `statement` once per value in batches of `CHUNK_SIZE`, sent in parallel. A
+/// `timestamp` in microseconds replaces the write time of every statement.
 pub async fn chunked_parallel_batch<T, S>(
     session: &Session,
     statement: S,
-    values: &Vec<T>,
+    values: &[T],
+    timestamp: Option<i64>,
 ) -> Result<Vec<QueryResult>, QueryError>
 where
     T: SerializeRow + Sync + Send + Clone,
@@ -36,6 +38,7 @@ where
     let futures = chunks.into_iter().map(|chunk| {
         let mut batch = Batch::default();
         batch.set_consistency(consistency);
+        batch.set_timestamp(timestamp);
         for _ in chunk {
             batch.append_statement(statement);
         }
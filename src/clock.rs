@@ -0,0 +1,17 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Milliseconds since the epoch, `duration` from now.
+pub fn millis_from_now(duration: Duration) -> i64 {
+    (since_epoch() + duration).as_millis() as i64
+}
+
+/// Microseconds since the epoch.
+pub fn micros_now() -> i64 {
+    since_epoch().as_micros() as i64
+}
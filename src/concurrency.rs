@@ -0,0 +1,172 @@
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::env::env_or;
+
+static CONCURRENCY_LIMITER: OnceLock<AdaptiveLimiter> = OnceLock::new();
+
+#[derive(Debug)]
+struct LimiterState {
+    limit: f64,
+    in_flight: usize,
+    last_decrease: Option<Instant>,
+    increases: u64,
+    decreases: u64,
+}
+
+/// Snapshot of the limiter for `/metrics`.
+#[derive(Debug, Serialize)]
+pub struct ConcurrencyMetrics {
+    pub limit: usize,
+    pub in_flight: usize,
+    pub min_limit: usize,
+    pub max_limit: usize,
+    /// Times the limit went up or down since startup
+    pub increases: u64,
+    pub decreases: u64,
+}
+
+/// Caps concurrent Spotify requests across every crawl in the process, adjusting the
+/// cap AIMD style: each fast success adds `1 / limit`, so a full window of them adds
+/// one, and a 429, timeout or response slower than the latency target multiplies it
+/// by `backoff`. Read from the environment:
+/// `SPOTIFY_CONCURRENCY_INITIAL` (16), `SPOTIFY_CONCURRENCY_MIN` (1),
+/// `SPOTIFY_CONCURRENCY_MAX` (64), `SPOTIFY_LATENCY_TARGET_MS` (2000) and
+/// `SPOTIFY_CONCURRENCY_BACKOFF` (0.5).
+pub struct AdaptiveLimiter {
+    min_limit: f64,
+    max_limit: f64,
+    latency_target: Duration,
+    backoff: f64,
+    state: Mutex<LimiterState>,
+    released: Notify,
+}
+
+/// A slot in the limiter, given back when dropped.
+pub struct ConcurrencyPermit<'a> {
+    limiter: &'a AdaptiveLimiter,
+    started: Instant,
+}
+
+impl AdaptiveLimiter {
+    pub fn from_env() -> Self {
+        let min_limit = env_or("SPOTIFY_CONCURRENCY_MIN", 1usize).max(1) as f64;
+        let max_limit = (env_or("SPOTIFY_CONCURRENCY_MAX", 64usize) as f64).max(min_limit);
+        let initial = env_or("SPOTIFY_CONCURRENCY_INITIAL", 16usize) as f64;
+        AdaptiveLimiter {
+            min_limit,
+            max_limit,
+            latency_target: Duration::from_millis(env_or("SPOTIFY_LATENCY_TARGET_MS", 2000)),
+            backoff: env_or("SPOTIFY_CONCURRENCY_BACKOFF", 0.5_f64).clamp(0.1, 0.9),
+            state: Mutex::new(LimiterState {
+                limit: initial.clamp(min_limit, max_limit),
+                in_flight: 0,
+                last_decrease: None,
+                increases: 0,
+                decreases: 0,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    /// Most requests that may ever run at once, for sizing fan-out.
+    pub fn max_limit(&self) -> usize {
+        self.max_limit as usize
+    }
+
+    /// Waits for a free slot.
+    pub async fn acquire(&self) -> ConcurrencyPermit<'_> {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // Registered before checking, so a release in between is not missed
+            released.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit as usize {
+                    state.in_flight += 1;
+                    return ConcurrencyPermit {
+                        limiter: self,
+                        started: Instant::now(),
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    fn succeeded(&self, latency: Duration) {
+        if latency > self.latency_target {
+            self.overloaded();
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let before = state.limit as usize;
+        state.limit = (state.limit + 1.0 / state.limit).min(self.max_limit);
+        if state.limit as usize > before {
+            state.increases += 1;
+            drop(state);
+            self.released.notify_waiters();
+        }
+    }
+
+    /// Cuts the limit, at most once per latency target so one burst of failures
+    /// counts once.
+    fn overloaded(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state
+            .last_decrease
+            .is_some_and(|last| now.duration_since(last) < self.latency_target)
+        {
+            return;
+        }
+        state.last_decrease = Some(now);
+        state.limit = (state.limit * self.backoff).max(self.min_limit);
+        state.decreases += 1;
+        println!(
+            "Cut Spotify request concurrency to {}",
+            state.limit as usize
+        );
+    }
+
+    pub fn metrics(&self) -> ConcurrencyMetrics {
+        let state = self.state.lock().unwrap();
+        ConcurrencyMetrics {
+            limit: state.limit as usize,
+            in_flight: state.in_flight,
+            min_limit: self.min_limit as usize,
+            max_limit: self.max_limit as usize,
+            increases: state.increases,
+            decreases: state.decreases,
+        }
+    }
+}
+
+impl ConcurrencyPermit<'_> {
+    /// Records a completed request, timed from when the permit was taken.
+    pub fn succeeded(&self) {
+        self.limiter.succeeded(self.started.elapsed());
+    }
+
+    /// Records a 429 or timeout.
+    pub fn overloaded(&self) {
+        self.limiter.overloaded();
+    }
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.released.notify_waiters();
+    }
+}
+
+pub fn concurrency_limiter() -> &'static AdaptiveLimiter {
+    CONCURRENCY_LIMITER.get_or_init(AdaptiveLimiter::from_env)
+}
@@ -1,56 +1,131 @@
 use std::{error::Error, time::Instant};
 
-use futures::future::join;
-use scylla::statement::Consistency;
+use futures::future::join3;
+use scylla::{
+    statement::Consistency,
+    transport::errors::{DbError, QueryError},
+};
 
 use crate::{
     batch::chunked_parallel_batch,
-    types::{Artist, NormalizedTrack},
+    types::{NormalizedAlbum, NormalizedArtist, NormalizedTrack},
 };
 
+/// Stores a crawl's albums, tracks and artists. With a `timestamp`, every write is
+/// made at that time, so it never overwrites cells written at a later one.
 pub async fn insert_data(
-    tracks: &Vec<NormalizedTrack>,
-    artists: &Vec<Artist>,
+    albums: &[NormalizedAlbum],
+    tracks: &[NormalizedTrack],
+    artists: &[NormalizedArtist],
     session: &scylla::Session,
+    timestamp: Option<i64>,
 ) -> Result<(), Box<dyn Error>> {
     let before = Instant::now();
 
-    match join(
+    match join3(
+        chunked_parallel_batch(
+            session,
+            "INSERT INTO music.albums (id, name, release_date, album_type, images, tracks) VALUES (?, ?, ?, ?, ?, ?)",
+            albums,
+            timestamp,
+        ),
         chunked_parallel_batch(
-            &session,
-            "INSERT INTO music.tracks (id, name, preview_url, artists) VALUES (?, ?, ?, ?)",
-            &tracks,
+            session,
+            "INSERT INTO music.tracks (id, name, preview_url, artists, release_date, album_id, album_name, album_image) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            tracks,
+            timestamp,
         ),
         chunked_parallel_batch(
-            &session,
+            session,
             "INSERT INTO music.artists (id, name) VALUES (?, ?)",
-            &artists,
+            artists,
+            timestamp,
         ),
     )
     .await
     {
-        (Ok(_), Ok(_)) => {}
-        (Err(e), _) => return Err(Box::new(e)),
-        (_, Err(e)) => return Err(Box::new(e)),
+        (Ok(_), Ok(_), Ok(_)) => {}
+        (Err(e), _, _) => return Err(Box::new(e)),
+        (_, Err(e), _) => return Err(Box::new(e)),
+        (_, _, Err(e)) => return Err(Box::new(e)),
     };
 
     println!("Insertion took {:?}", before.elapsed());
 
     Ok(())
 }
+/// Adds a column to a table created by an older version of the schema.
+pub async fn add_column(
+    session: &scylla::Session,
+    table: &str,
+    column: &str,
+    cql_type: &str,
+) -> Result<(), Box<dyn Error>> {
+    let statement = format!("ALTER TABLE {} ADD {} {}", table, column, cql_type);
+    match session.query(statement, ()).await {
+        Ok(_) => Ok(()),
+        Err(QueryError::DbError(DbError::Invalid, message))
+            if message.contains("conflicts with an existing column") =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// Whether the one-off migration `name` has already run to completion.
+pub async fn migrated(session: &scylla::Session, name: &str) -> Result<bool, Box<dyn Error>> {
+    let result = session
+        .query("SELECT name FROM music.migrations WHERE name = ?", (name,))
+        .await?;
+    Ok(result.rows_num().unwrap_or(0) > 0)
+}
+
+pub async fn mark_migrated(session: &scylla::Session, name: &str) -> Result<(), Box<dyn Error>> {
+    session
+        .query(
+            "INSERT INTO music.migrations (name, applied_at) VALUES (?, currentTimestamp())",
+            (name,),
+        )
+        .await?;
+    Ok(())
+}
+
 pub async fn setup_keyspace(session: &scylla::Session) -> Result<(), Box<dyn Error>> {
     let mut  prepared = session.prepare("CREATE KEYSPACE IF NOT EXISTS music WITH REPLICATION = {'class' : 'SimpleStrategy', 'replication_factor' : 1}").await?;
     prepared.set_consistency(Consistency::All);
     session.execute(&prepared, ()).await?;
     // Create the music.tracks table
-    let mut prepared = session.prepare("CREATE TABLE IF NOT EXISTS music.tracks (id text, created_at timestamp, name text, preview_url text, artists list<text>, PRIMARY KEY (id))").await?;
+    let mut prepared = session.prepare("CREATE TABLE IF NOT EXISTS music.tracks (id text, created_at timestamp, name text, preview_url text, artists list<text>, release_date text, album_id text, album_name text, album_image text, PRIMARY KEY (id))").await?;
     prepared.set_consistency(Consistency::All);
     session.execute(&prepared, ()).await?;
+    add_column(session, "music.tracks", "release_date", "text").await?;
+    add_column(session, "music.tracks", "album_id", "text").await?;
+    add_column(session, "music.tracks", "album_name", "text").await?;
+    add_column(session, "music.tracks", "album_image", "text").await?;
     // Create the music.artists table
     let mut prepared = session
         .prepare("CREATE TABLE IF NOT EXISTS music.artists (id text, created_at timestamp, name text, PRIMARY KEY (id))")
         .await?;
     prepared.set_consistency(Consistency::All);
     session.execute(&prepared, ()).await?;
+    // Create the music.albums table
+    let mut prepared = session
+        .prepare("CREATE TABLE IF NOT EXISTS music.albums (id text, created_at timestamp, name text, release_date text, album_type text, images list<text>, tracks list<text>, PRIMARY KEY (id))")
+        .await?;
+    prepared.set_consistency(Consistency::All);
+    session.execute(&prepared, ()).await?;
+    // Newest lock token that wrote each artist's crawl, see `lock::ArtistLock::fence`
+    let mut prepared = session
+        .prepare("CREATE TABLE IF NOT EXISTS music.artist_fences (artist_id text, token bigint, PRIMARY KEY (artist_id))")
+        .await?;
+    prepared.set_consistency(Consistency::All);
+    session.execute(&prepared, ()).await?;
+    // One-off data migrations that have run, see `migrated`
+    let mut prepared = session
+        .prepare("CREATE TABLE IF NOT EXISTS music.migrations (name text, applied_at timestamp, PRIMARY KEY (name))")
+        .await?;
+    prepared.set_consistency(Consistency::All);
+    session.execute(&prepared, ()).await?;
     Ok(())
 }
@@ -0,0 +1,347 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::graph::CollaborationGraph;
+use crate::path::{Budget, Exclusions};
+
+/// Paths found by a unit-capacity max-flow run. `complete` is false when the search
+/// stopped early (budget spent or `max_paths` reached), in which case the paths are
+/// still disjoint but the set may not be maximal.
+pub struct DisjointPaths {
+    pub paths: Vec<Vec<u32>>,
+    pub complete: bool,
+}
+
+/// Maximum set of paths that share no collaboration (artist pair), found with
+/// Edmonds-Karp over the undirected graph where every collaboration has capacity 1.
+///
+/// The unit is the artist pair, not the track: a track by A, B and C links A-B, B-C
+/// and A-C, so one path may use it between A and B and another between B and C.
+pub fn edge_disjoint_paths(
+    graph: &CollaborationGraph,
+    source: u32,
+    target: u32,
+    max_paths: usize,
+    exclusions: &Exclusions,
+    budget: &mut Budget,
+) -> DisjointPaths {
+    // Antisymmetric flow: flow[(u, v)] == -flow[(v, u)]
+    let mut flow: HashMap<(u32, u32), i8> = HashMap::new();
+    let mut count = 0;
+    let mut complete = true;
+
+    while count < max_paths {
+        let mut parents: HashMap<u32, u32> = HashMap::from([(source, source)]);
+        let mut queue = VecDeque::from([source]);
+        let mut reached = false;
+        'search: while let Some(artist) = queue.pop_front() {
+            if !budget.spend() {
+                complete = false;
+                break;
+            }
+            for edge in graph.neighbors(artist) {
+                let residual = flow.get(&(artist, edge.neighbor)).copied().unwrap_or(0) < 1;
+                if residual
+                    && !parents.contains_key(&edge.neighbor)
+                    && exclusions.allows(graph, artist, edge)
+                {
+                    parents.insert(edge.neighbor, artist);
+                    if edge.neighbor == target {
+                        reached = true;
+                        break 'search;
+                    }
+                    queue.push_back(edge.neighbor);
+                }
+            }
+        }
+        if !reached {
+            break;
+        }
+
+        let mut current = target;
+        while current != source {
+            let parent = parents[&current];
+            *flow.entry((parent, current)).or_default() += 1;
+            *flow.entry((current, parent)).or_default() -= 1;
+            current = parent;
+        }
+        count += 1;
+    }
+    if count == max_paths {
+        complete = false;
+    }
+
+    let mut successors: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (&(from, to), &units) in &flow {
+        if units > 0 {
+            successors.entry(from).or_default().push(to);
+        }
+    }
+
+    let mut paths = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut path = vec![source];
+        let mut current = source;
+        while current != target {
+            let next = successors
+                .get_mut(&current)
+                .and_then(Vec::pop)
+                .expect("flow is conserved at every intermediate artist");
+            // Flow decomposition may wander through a cycle; cut it out to keep the path simple
+            match path.iter().position(|&artist| artist == next) {
+                Some(position) => path.truncate(position + 1),
+                None => path.push(next),
+            }
+            current = next;
+        }
+        paths.push(path);
+    }
+
+    DisjointPaths { paths, complete }
+}
+
+/// Maximum set of paths that share no intermediate artist.
+///
+/// Runs Edmonds-Karp over the split graph where every intermediate artist becomes an
+/// `in` and an `out` node joined by an arc of capacity 1. The split is implicit: a
+/// search state is an artist plus whether it is on the `out` side.
+pub fn node_disjoint_paths(
+    graph: &CollaborationGraph,
+    source: u32,
+    target: u32,
+    max_paths: usize,
+    exclusions: &Exclusions,
+    budget: &mut Budget,
+) -> DisjointPaths {
+    // Collaboration arcs `u out -> v in` carrying flow
+    let mut arcs: HashSet<(u32, u32)> = HashSet::new();
+    // Intermediate artists whose `in -> out` arc carries flow
+    let mut used: HashSet<u32> = HashSet::new();
+    let mut count = 0;
+    let mut complete = true;
+
+    while count < max_paths {
+        let start = (source, true);
+        let mut parents: HashMap<(u32, bool), (u32, bool)> = HashMap::from([(start, start)]);
+        let mut queue = VecDeque::from([start]);
+        let mut reached = false;
+        'search: while let Some(state) = queue.pop_front() {
+            if !budget.spend() {
+                complete = false;
+                break;
+            }
+            let (artist, out) = state;
+            let mut next_states = Vec::new();
+            if out {
+                for edge in graph.neighbors(artist) {
+                    if edge.neighbor != source
+                        && !arcs.contains(&(artist, edge.neighbor))
+                        && exclusions.allows(graph, artist, edge)
+                    {
+                        next_states.push((edge.neighbor, false));
+                    }
+                }
+                if used.contains(&artist) && artist != source {
+                    next_states.push((artist, false));
+                }
+            } else {
+                if !used.contains(&artist) {
+                    next_states.push((artist, true));
+                }
+                for edge in graph.neighbors(artist) {
+                    if arcs.contains(&(edge.neighbor, artist)) {
+                        next_states.push((edge.neighbor, true));
+                    }
+                }
+            }
+            for next in next_states {
+                if parents.contains_key(&next) {
+                    continue;
+                }
+                parents.insert(next, state);
+                if next == (target, false) {
+                    reached = true;
+                    break 'search;
+                }
+                queue.push_back(next);
+            }
+        }
+        if !reached {
+            break;
+        }
+
+        let mut current = (target, false);
+        while current != start {
+            let parent = parents[&current];
+            match (parent, current) {
+                ((from, true), (to, false)) if from != to => {
+                    arcs.insert((from, to));
+                }
+                ((from, false), (to, true)) if from != to => {
+                    arcs.remove(&(to, from));
+                }
+                ((artist, false), (_, true)) => {
+                    used.insert(artist);
+                }
+                ((artist, true), (_, false)) => {
+                    used.remove(&artist);
+                }
+                _ => unreachable!("search only moves between in and out states"),
+            }
+            current = parent;
+        }
+        count += 1;
+    }
+    if count == max_paths {
+        complete = false;
+    }
+
+    // Node capacities leave every intermediate artist with exactly one outgoing arc
+    let mut successors: HashMap<u32, Vec<u32>> = HashMap::new();
+    for &(from, to) in &arcs {
+        successors.entry(from).or_default().push(to);
+    }
+
+    let paths = successors
+        .remove(&source)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|first| {
+            let mut path = vec![source, first];
+            let mut current = first;
+            while current != target {
+                current = successors[&current][0];
+                path.push(current);
+            }
+            path
+        })
+        .collect();
+
+    DisjointPaths { paths, complete }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn graph(tracks: &[&[&str]]) -> CollaborationGraph {
+        let mut graph = CollaborationGraph::default();
+        for (position, artists) in tracks.iter().enumerate() {
+            let artists: Vec<String> = artists.iter().map(|id| id.to_string()).collect();
+            graph.add_track(&format!("t{}", position), &artists, None);
+        }
+        graph
+    }
+
+    fn run(
+        search: fn(&CollaborationGraph, u32, u32, usize, &Exclusions, &mut Budget) -> DisjointPaths,
+        graph: &CollaborationGraph,
+        max_paths: usize,
+    ) -> DisjointPaths {
+        search(
+            graph,
+            graph.artist_index("s").unwrap(),
+            graph.artist_index("t").unwrap(),
+            max_paths,
+            &Exclusions::default(),
+            &mut Budget::new(10_000),
+        )
+    }
+
+    fn assert_valid(graph: &CollaborationGraph, paths: &[Vec<u32>]) {
+        for path in paths {
+            assert_eq!(graph.artist_id(path[0]), "s");
+            assert_eq!(graph.artist_id(*path.last().unwrap()), "t");
+            for pair in path.windows(2) {
+                assert!(graph.edge(pair[0], pair[1]).is_some());
+            }
+        }
+    }
+
+    /// Two routes through `m`, plus one through `x` and `y`:
+    /// s-a-m-b-t, s-c-m-d-t and s-x-y-t.
+    fn bottleneck() -> CollaborationGraph {
+        graph(&[
+            &["s", "a"],
+            &["a", "m"],
+            &["m", "b"],
+            &["b", "t"],
+            &["s", "c"],
+            &["c", "m"],
+            &["m", "d"],
+            &["d", "t"],
+            &["s", "x"],
+            &["x", "y"],
+            &["y", "t"],
+        ])
+    }
+
+    #[test]
+    fn node_disjoint_paths_share_no_intermediate_artist() {
+        let graph = bottleneck();
+        let result = run(node_disjoint_paths, &graph, 10);
+        assert!(result.complete);
+        assert_eq!(result.paths.len(), 2);
+        assert_valid(&graph, &result.paths);
+        let mut seen = HashSet::new();
+        for path in &result.paths {
+            for &artist in &path[1..path.len() - 1] {
+                assert!(seen.insert(artist), "artist shared between paths");
+            }
+        }
+    }
+
+    #[test]
+    fn edge_disjoint_paths_may_share_artists_but_not_pairs() {
+        let graph = bottleneck();
+        let result = run(edge_disjoint_paths, &graph, 10);
+        assert!(result.complete);
+        assert_eq!(result.paths.len(), 3);
+        assert_valid(&graph, &result.paths);
+        let mut seen = HashSet::new();
+        for path in &result.paths {
+            for pair in path.windows(2) {
+                let key = (pair[0].min(pair[1]), pair[0].max(pair[1]));
+                assert!(seen.insert(key), "collaboration shared between paths");
+            }
+        }
+    }
+
+    #[test]
+    fn disjoint_paths_reroute_through_earlier_paths() {
+        // The shortest path s-a-b-t blocks both others unless the flow is pushed back
+        // over a-b: the maximum is s-a-d-t and s-c-b-t
+        let graph = graph(&[
+            &["s", "a"],
+            &["a", "b"],
+            &["b", "t"],
+            &["a", "d"],
+            &["d", "t"],
+            &["s", "c"],
+            &["c", "b"],
+        ]);
+        for search in [node_disjoint_paths, edge_disjoint_paths] {
+            let result = run(search, &graph, 10);
+            assert!(result.complete);
+            assert_eq!(result.paths.len(), 2);
+            assert_valid(&graph, &result.paths);
+        }
+    }
+
+    #[test]
+    fn disjoint_paths_stop_at_max_paths() {
+        let graph = bottleneck();
+        let result = run(edge_disjoint_paths, &graph, 1);
+        assert!(!result.complete);
+        assert_eq!(result.paths.len(), 1);
+    }
+
+    #[test]
+    fn edge_disjoint_paths_can_reuse_a_track_through_different_pairs() {
+        // One track by s, m and t links s-t directly and s-m, m-t
+        let graph = graph(&[&["s", "m", "t"]]);
+        let result = run(edge_disjoint_paths, &graph, 10);
+        assert_eq!(result.paths.len(), 2);
+    }
+}
@@ -0,0 +1,10 @@
+use std::str::FromStr;
+
+/// The environment variable `name` parsed as `T`, or `default` when it is unset or
+/// does not parse.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
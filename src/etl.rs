@@ -1,107 +1,225 @@
 use crate::db::insert_data;
 use crate::fetch::{fetch_albums_with_tracks, fetch_all_items, SPOTIFY_API_BASE};
-use itertools::Itertools;
 
-use crate::task::enqueue_tasks;
-use crate::types::{Album, NormalizedTrack, Track};
+use crate::lock::{lock_ttl, ArtistLock};
+use crate::processed::ProcessedArtists;
+use crate::queue::TaskQueue;
+use crate::retry::retry_policy;
+use crate::task::{max_depth, Discovery};
+use crate::token::TokenManager;
+use crate::types::{normalize_albums, Album, NormalizedArtist, NormalizedTrack};
 
+use itertools::Itertools;
 use reqwest::{self};
-use std::{collections::HashSet, time::Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
 
 use fred::prelude::*;
 use std::error::Error;
 
+/// Prefix of the per-artist sets of album ids whose tracks were already stored
+const INGESTED_ALBUMS_PREFIX: &str = "ingested_albums";
+
+/// What a processed artist added to the catalogue, for updating in-memory indexes.
+pub struct ProcessedArtist {
+    /// Collaboration tracks only.
+    pub tracks: Vec<NormalizedTrack>,
+    pub artists: Vec<NormalizedArtist>,
+}
+
+/// How an attempt to crawl one artist ended.
+pub enum CrawlOutcome {
+    Crawled(ProcessedArtist),
+    /// Another instance holds the artist's lock, so nothing was crawled.
+    Locked,
+}
+
+/// Crawls one artist `depth` hops from the nearest seed and queues the collaborators
+/// it turns up one hop further, unless that is beyond `CRAWL_MAX_DEPTH`. Their albums
+/// are stored either way. Only albums not ingested by an earlier crawl of the artist
+/// are fetched, so re-crawls pick up new releases.
+///
+/// Returns `CrawlOutcome::Locked` without crawling if another instance is already
+/// crawling the artist.
 pub async fn process_artist(
     artist_id: &str,
+    depth: i32,
     redis_client: &fred::prelude::RedisClient,
     session: &scylla::Session,
-    auth_token: &str,
+    queue: &dyn TaskQueue,
+    tokens: &TokenManager,
     http_client: &reqwest::Client,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<CrawlOutcome, Box<dyn Error>> {
     println!("Processing artist {:?}", artist_id);
-    let lock_key = format!("lock:artist:{}", artist_id);
-    let lock_result: bool = redis_client
-        .set(
-            &lock_key,
-            "locked",
-            Some(Expiration::EX(30)),
-            Some(SetOptions::NX),
-            false,
+    let ttl = lock_ttl();
+    let Some(lock) = ArtistLock::acquire(redis_client, artist_id, ttl).await? else {
+        println!(
+            "Artist {} is already being processed by another instance",
+            artist_id
+        );
+        return Ok(CrawlOutcome::Locked);
+    };
+    println!("Locked artist {:?}", artist_id);
+    let processed = ProcessedArtists::new(redis_client);
+
+    let crawl = async {
+        let albums_url = format!("{}/artists/{}/albums?limit=50", SPOTIFY_API_BASE, artist_id);
+        let albums_raw: Vec<Album> = fetch_all_items(http_client, &albums_url, tokens).await?;
+        println!("Fetched albums {:?}", albums_raw.len());
+
+        let ingested_key = format!("{}:{}", INGESTED_ALBUMS_PREFIX, artist_id);
+        let crawled_depth = processed.depth(artist_id).await?;
+        // Reached closer to the seeds than last time, so collaborators on old albums are
+        // queued again at the new depth
+        let ingested: HashSet<String> =
+            if crawled_depth.is_some_and(|crawled_depth| depth < crawled_depth) {
+                HashSet::new()
+            } else {
+                redis_client.smembers(&ingested_key).await?
+            };
+        let new_albums: Vec<&str> = albums_raw
+            .iter()
+            .map(|a| a.id.as_str())
+            .filter(|id| !ingested.contains(*id))
+            .collect();
+        println!(
+            "Fetching tracks of {} new albums out of {}",
+            new_albums.len(),
+            albums_raw.len()
+        );
+
+        let (albums, incomplete) =
+            fetch_albums_with_tracks(http_client, new_albums, tokens).await?;
+        let (all_albums, all_tracks, all_artists) = normalize_albums(albums);
+        // Only collaborations make it into the graph; albums still list every track
+        let all_tracks: Vec<NormalizedTrack> = all_tracks
+            .into_iter()
+            .filter(|t| t.artists.len() > 1)
+            .collect();
+
+        if !lock.fence(session).await? {
+            return Err(format!("A newer crawl of artist {} already wrote", artist_id).into());
+        }
+        insert_data(
+            &all_albums,
+            &all_tracks,
+            &all_artists,
+            session,
+            Some(lock.token),
         )
         .await?;
-    println!("Locked artist {:?}", artist_id);
 
-    // if !lock_result {
-    //     println!(
-    //         "Artist {} is already being processed by another instance",
-    //         artist_id
-    //     );
-    //     return Ok(());
-    // }
-
-    let albums_url = format!("{}/artists/{}/albums?limit=50", SPOTIFY_API_BASE, artist_id);
-    let albums_raw: Vec<Album> = fetch_all_items(&http_client, &albums_url, auth_token).await?;
-    println!("Fetched albums {:?}", albums_raw.len());
-
-    let all_tracks_base: Vec<Track> = fetch_albums_with_tracks(
-        &http_client,
-        albums_raw.iter().map(|a| a.id.as_str()).collect(),
-        &auth_token,
-    )
-    .await?;
-    let all_tracks: &Vec<NormalizedTrack> = &all_tracks_base
-        .clone()
-        .into_iter()
-        .filter(|t| t.artists.len() > 1)
-        .map(|track| NormalizedTrack {
-            id: track.id,
-            name: track.name,
-            preview_url: track.preview_url,
-            artists: track.artists.iter().map(|a| a.id.clone()).collect(),
+        println!("Mutated artist {:?}", artist_id);
+        let artist_ids: Vec<&str> = all_artists.iter().map(|a| a.id.as_str()).unique().collect();
+        let before = Instant::now();
+        let crawled = processed.contains(&artist_ids).await?;
+        println!("Checked processed artists in {:?}", before.elapsed());
+        let mut processed_artists = Vec::new();
+        let mut unprocessed_artists = Vec::new();
+        for (&id, crawled) in artist_ids.iter().zip(crawled) {
+            if crawled {
+                processed_artists.push(id);
+            } else {
+                unprocessed_artists.push(id);
+            }
+        }
+
+        let discovered_depth = depth + 1;
+        let max_depth = max_depth();
+        if let Some(max_depth) = max_depth.filter(|&max_depth| discovered_depth > max_depth) {
+            println!(
+                "Not queueing {} artists beyond the maximum crawl depth of {}",
+                unprocessed_artists.len(),
+                max_depth
+            );
+            unprocessed_artists.clear();
+        } else if max_depth.is_some() {
+            // Artists first crawled further from the seeds stopped queueing their
+            // collaborators earlier than they would from here, so they are crawled again
+            let crawled: Vec<&str> = processed_artists
+                .into_iter()
+                .filter(|&id| id != artist_id)
+                .collect();
+            let depths = processed.depths(&crawled).await?;
+            let closer = crawled
+                .into_iter()
+                .zip(depths)
+                .filter(|(_, crawled_depth)| crawled_depth.is_some_and(|d| d > discovered_depth))
+                .map(|(id, _)| id);
+            unprocessed_artists.extend(closer);
+        }
+
+        // Send unprocessed artists to RabbitMQ
+        let before = Instant::now();
+        // Artists found on many of this artist's collaborations are crawled first
+        let mut weights: HashMap<&str, i32> = HashMap::new();
+        for track in &all_tracks {
+            for artist in &track.artists {
+                *weights.entry(artist.as_str()).or_default() += 1;
+            }
+        }
+        // Discoveries only add to the queue and never overwrite another holder's, so
+        // checking the lock first is enough here
+        if !lock.held().await? {
+            return Err(format!("Lost the lock on artist {}", artist_id).into());
+        }
+        queue
+            .enqueue(
+                unprocessed_artists
+                    .iter()
+                    .map(|&artist_id| Discovery {
+                        artist_id: artist_id.to_string(),
+                        weight: weights.get(artist_id).copied().unwrap_or(1),
+                        depth: discovered_depth,
+                    })
+                    .collect(),
+            )
+            .await?;
+        println!("Published artists to process in {:?}", before.elapsed());
+        println!(
+            "Published artists to process: {:?}",
+            unprocessed_artists.len()
+        );
+
+        // Mark initial artist as processed in Redis. Albums missing tracks stay out of
+        // the ingested set so the next crawl fetches them again
+        let before = Instant::now();
+        let complete_albums: Vec<&str> = all_albums
+            .iter()
+            .map(|a| a.id.as_str())
+            .filter(|id| !incomplete.contains(*id))
+            .collect();
+        if !processed
+            .insert(&lock, artist_id, depth, &ingested_key, &complete_albums)
+            .await?
+        {
+            return Err(format!("Lost the lock on artist {}", artist_id).into());
+        }
+        println!(
+            "Marked artist {:?} as processed in {:?}",
+            artist_id,
+            before.elapsed()
+        );
+
+        Ok::<_, Box<dyn Error>>(ProcessedArtist {
+            tracks: all_tracks,
+            artists: all_artists,
         })
-        .collect();
-    let all_artists = &all_tracks_base
-        .into_iter()
-        .flat_map(|t| t.artists)
-        .unique()
-        .collect::<Vec<_>>();
-
-    insert_data(&all_tracks, &all_artists, &session).await?;
-
-    println!("Mutated artist {:?}", artist_id);
-    let artist_id_set: HashSet<String> = all_artists.into_iter().map(|a| a.id.clone()).collect();
-    // // Check processed artists in Redis
-    let before = Instant::now();
-    let processed_artists: HashSet<String> = redis_client.smembers("processed_artists").await?;
-    println!("Fetched processed artists in {:?}", before.elapsed());
-    let unprocessed_artists: HashSet<_> = artist_id_set.difference(&processed_artists).collect();
-
-    // Send unprocessed artists to RabbitMQ
-    let before = Instant::now();
-    enqueue_tasks(
-        &session,
-        unprocessed_artists
-            .to_owned()
-            .into_iter()
-            .cloned()
-            .collect(),
-    )
-    .await?;
-    println!("Published artists to process in {:?}", before.elapsed());
-    println!(
-        "Published artists to process: {:?}",
-        unprocessed_artists.len()
-    );
-
-    // Mark initial artist as processed in Redis
-    let before = Instant::now();
-    redis_client.sadd("processed_artists", artist_id).await?;
-    redis_client.del(&lock_key).await?;
-    println!(
-        "Marked artist {:?} as processed in {:?}",
-        artist_id,
-        before.elapsed()
-    );
-
-    Ok(())
+    };
+    let deadline = retry_policy().artist_deadline;
+    let crawl = async {
+        tokio::time::timeout(deadline, crawl)
+            .await
+            .map_err(|_| format!("Crawling artist {} took over {:?}", artist_id, deadline))?
+    };
+    let result = lock.while_held(ttl, crawl).await;
+    if let Err(e) = lock.release().await {
+        eprintln!(
+            "Failed to release the lock on artist {}: {:?}",
+            artist_id, e
+        );
+    }
+    result.map(CrawlOutcome::Crawled)
 }
@@ -30,7 +30,8 @@ impl From<regex::Error> for ApiKeyError {
     }
 }
 
-pub async fn get_api_key(client: &reqwest::Client) -> Result<String, Box<dyn Error>> {
+/// Scrapes the anonymous web player token embedded in the open.spotify.com page.
+pub async fn get_api_key(client: &reqwest::Client) -> Result<AccessToken, Box<dyn Error>> {
     let text = client
         .get("https://open.spotify.com")
         .send()
@@ -38,124 +39,255 @@ pub async fn get_api_key(client: &reqwest::Client) -> Result<String, Box<dyn Err
         .text()
         .await?;
     let re = Regex::new(r#""accessToken":\s*"([^"]+)""#)?;
+    let expiry_re = Regex::new(r#""accessTokenExpirationTimestampMs":\s*(\d+)"#)?;
     // println!("{:?}",text);
     if let Some(caps) = re.captures(&text) {
         if let Some(token) = caps.get(1) {
-            return Ok(token.as_str().to_string());
+            let expires_at = expiry_re
+                .captures(&text)
+                .and_then(|caps| caps.get(1))
+                .and_then(|expiry| expiry.as_str().parse().ok())
+                .unwrap_or_else(|| millis_from_now(SCRAPED_TOKEN_LIFETIME));
+            return Ok(AccessToken {
+                value: token.as_str().to_string(),
+                expires_at,
+            });
         }
     }
     Err(Box::new(ApiKeyError::TokenNotFound))
 }
 use futures::{stream, StreamExt};
 use regex::Regex;
-use reqwest::{header, Client};
-use serde::{Deserialize, Serialize};
+use reqwest::{header, Client, StatusCode};
 use serde_json::{json, Value};
-use std::error::Error;
+use std::{
+    collections::HashSet,
+    error::Error,
+    time::{Duration, SystemTime},
+};
 
-use crate::types::Track;
+use crate::clock::millis_from_now;
+use crate::concurrency::concurrency_limiter;
+use crate::rate_limit::rate_limiter;
+use crate::retry::retry_policy;
+use crate::token::{AccessToken, TokenManager};
+use crate::types::{Album, Track};
 
-const CONCURRENT_REQUESTS: usize = 16;
 const TRACKS_LIMIT: usize = 20;
+/// Assumed lifetime of a scraped token whose page does not say when it expires
+const SCRAPED_TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+/// Wait after a 429 that does not say how long to wait
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
 
+/// GETs a Spotify API url through the shared rate and concurrency limiters. A 429
+/// pauses every request for its `Retry-After` and is then retried, up to the retry
+/// policy's `max_rate_limited` times; transient failures
+/// are retried as the retry policy allows, other error statuses fail. A 401 refreshes
+/// the token and is retried once. Slots are given back while waiting to retry.
+async fn get_json(
+    client: &Client,
+    url: &str,
+    tokens: &TokenManager,
+) -> Result<Value, Box<dyn Error>> {
+    let policy = retry_policy();
+    let mut attempt = 1;
+    let mut rate_limited = 0;
+    let mut token_refreshed = false;
+    loop {
+        let auth_token = tokens.current().await;
+        rate_limiter().acquire().await?;
+        let permit = concurrency_limiter().acquire().await;
+        let result = client
+            .get(url)
+            .header("Authorization", format!("Bearer {}", auth_token))
+            .timeout(policy.request_timeout)
+            .send()
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                if e.is_timeout() {
+                    permit.overloaded();
+                }
+                if policy.retries_error(&e) && attempt < policy.max_attempts {
+                    drop(permit);
+                    attempt = retry_after_failure(url, attempt, &e).await;
+                    continue;
+                }
+                return Err(e.into());
+            }
+        };
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            permit.overloaded();
+            drop(permit);
+            let retry_after = response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after)
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            rate_limiter().pause(retry_after).await?;
+            if rate_limited >= policy.max_rate_limited {
+                return Err(format!(
+                    "Still rate limited by Spotify after {} retries of {}",
+                    rate_limited, url
+                )
+                .into());
+            }
+            rate_limited += 1;
+            eprintln!("Rate limited by Spotify, retrying in {:?}", retry_after);
+            continue;
+        }
+        if status == StatusCode::UNAUTHORIZED && !token_refreshed {
+            drop(permit);
+            eprintln!("Token rejected by Spotify, refreshing it");
+            tokens.refresh_now(&auth_token).await?;
+            token_refreshed = true;
+            continue;
+        }
+        if policy.retries_status(status) && attempt < policy.max_attempts {
+            if status.is_server_error() {
+                permit.overloaded();
+            }
+            drop(permit);
+            attempt = retry_after_failure(url, attempt, &status).await;
+            continue;
+        }
+        match response.error_for_status()?.json().await {
+            Ok(body) => {
+                permit.succeeded();
+                return Ok(body);
+            }
+            Err(e) if policy.retries_error(&e) && attempt < policy.max_attempts => {
+                drop(permit);
+                attempt = retry_after_failure(url, attempt, &e).await;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Reads a `Retry-After` value, either seconds to wait or an HTTP date to wait until.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let until = httpdate::parse_http_date(value).ok()?;
+    Some(
+        until
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Sleeps out the backoff after failed try `attempt` and returns the next attempt.
+async fn retry_after_failure(url: &str, attempt: u32, reason: &dyn std::fmt::Display) -> u32 {
+    let delay = retry_policy().delay(attempt);
+    eprintln!(
+        "Request to {} failed ({}), retrying in {:?}",
+        url, reason, delay
+    );
+    tokio::time::sleep(delay).await;
+    attempt + 1
+}
+
+/// Fetches the albums with every track. Also returns the ids of albums whose tracks
+/// past the first `TRACKS_LIMIT` could not be fetched; those albums only carry their
+/// first tracks and must be fetched again later.
 pub async fn fetch_albums_with_tracks(
     client: &Client,
     all_albums: Vec<&str>,
-    auth_token: &str,
-) -> Result<Vec<Track>, Box<dyn Error>> {
+    tokens: &TokenManager,
+) -> Result<(Vec<Album>, HashSet<String>), Box<dyn Error>> {
     let album_chunks: Vec<Vec<&str>> = all_albums.chunks(20).map(|chunk| chunk.to_vec()).collect();
 
     let albums_with_tracks = stream::iter(album_chunks)
         .map(|chunk| {
             let client = client.clone();
             let ids = chunk.join(",");
-            async move { fetch_albums_with_initial_tracks(&client, &ids, auth_token).await }
+            async move { fetch_albums_with_initial_tracks(&client, &ids, tokens).await }
         })
-        .buffer_unordered(CONCURRENT_REQUESTS)
+        .buffer_unordered(concurrency_limiter().max_limit())
         .collect::<Vec<_>>()
         .await;
 
-    let mut all_tracks = Vec::new();
-    let mut albums_needing_more_tracks = Vec::new();
-
+    let mut all_albums = Vec::new();
     for result in albums_with_tracks {
         match result {
-            Ok((albums, tracks)) => {
-                all_tracks.extend(tracks);
-                for album in albums {
-                    if album.total_tracks > TRACKS_LIMIT {
-                        albums_needing_more_tracks.push(album);
-                    }
-                }
-            }
+            Ok(albums) => all_albums.extend(albums),
             Err(e) => eprintln!("Error fetching albums: {}", e),
         }
     }
 
     // Fetch remaining tracks for albums with more than 20 tracks
+    let albums_needing_more_tracks: Vec<(usize, String, usize)> = all_albums
+        .iter()
+        .enumerate()
+        .filter(|(_, album)| album.total_tracks > TRACKS_LIMIT)
+        .map(|(index, album)| (index, album.id.clone(), album.total_tracks))
+        .collect();
     let additional_tracks = stream::iter(albums_needing_more_tracks)
-        .map(|album| {
+        .map(|(index, id, total_tracks)| {
             let client = client.clone();
             async move {
-                fetch_remaining_tracks(&client, &album.id, album.total_tracks, auth_token).await
+                let tracks = fetch_remaining_tracks(&client, &id, total_tracks, tokens).await;
+                (index, tracks)
             }
         })
-        .buffer_unordered(CONCURRENT_REQUESTS)
+        .buffer_unordered(concurrency_limiter().max_limit())
         .collect::<Vec<_>>()
         .await;
 
-    for result in additional_tracks {
+    let mut incomplete = HashSet::new();
+    for (index, result) in additional_tracks {
         match result {
-            Ok(tracks) => all_tracks.extend(tracks),
-            Err(e) => eprintln!("Error fetching additional tracks: {}", e),
+            Ok(tracks) => all_albums[index].tracks.extend(tracks),
+            Err(e) => {
+                eprintln!("Error fetching additional tracks: {}", e);
+                incomplete.insert(all_albums[index].id.clone());
+            }
         }
     }
 
-    Ok(all_tracks)
+    Ok((all_albums, incomplete))
 }
 
 async fn fetch_albums_with_initial_tracks(
     client: &Client,
     ids: &str,
-    auth_token: &str,
-) -> Result<(Vec<AlbumInfo>, Vec<Track>), Box<dyn Error>> {
+    tokens: &TokenManager,
+) -> Result<Vec<Album>, Box<dyn Error>> {
     let url = format!("{}/albums?ids={}", SPOTIFY_API_BASE, ids);
-    let response: Value = client
-        .get(&url)
-        .header("Authorization", format!("Bearer {}", auth_token))
-        .send()
-        .await?
-        .json()
-        .await?;
+    let mut response = get_json(client, &url, tokens).await?;
 
     let mut albums = Vec::new();
-    let mut tracks = Vec::new();
-
-    if let Some(albums_array) = response["albums"].as_array() {
-        for album in albums_array {
-            if let (Some(id), Some(total_tracks)) =
-                (album["id"].as_str(), album["total_tracks"].as_u64())
-            {
-                albums.push(AlbumInfo {
-                    id: id.to_string(),
-                    total_tracks: total_tracks as usize,
-                });
-            }
 
-            if let Some(items) = album["tracks"]["items"].as_array() {
-                tracks.extend(serde_json::from_value::<Vec<Track>>(json!(items))?);
-            }
+    if let Some(albums_array) = response["albums"].as_array_mut() {
+        for album in albums_array.iter_mut() {
+            // Unavailable ids come back as null entries
+            let Some(fields) = album.as_object_mut() else {
+                continue;
+            };
+            let items = fields
+                .remove("tracks")
+                .map(|tracks| tracks["items"].clone())
+                .unwrap_or(json!([]));
+            let mut album = serde_json::from_value::<Album>(album.take())?;
+            album.tracks = serde_json::from_value::<Vec<Track>>(items)?;
+            albums.push(album);
         }
     }
 
-    Ok((albums, tracks))
+    Ok(albums)
 }
 
 async fn fetch_remaining_tracks(
     client: &Client,
     album_id: &str,
     total_tracks: usize,
-    auth_token: &str,
+    tokens: &TokenManager,
 ) -> Result<Vec<Track>, Box<dyn Error>> {
     let mut all_tracks = Vec::new();
     let mut offset = TRACKS_LIMIT;
@@ -165,13 +297,7 @@ async fn fetch_remaining_tracks(
             "{}/albums/{}/tracks?offset={}&limit=50",
             SPOTIFY_API_BASE, album_id, offset
         );
-        let response: Value = client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", auth_token))
-            .send()
-            .await?
-            .json()
-            .await?;
+        let response = get_json(client, &url, tokens).await?;
 
         if let Some(items) = response["items"].as_array() {
             all_tracks.extend(serde_json::from_value::<Vec<Track>>(json!(items))?);
@@ -182,29 +308,17 @@ async fn fetch_remaining_tracks(
 
     Ok(all_tracks)
 }
-#[derive(Debug, Serialize, Deserialize)]
-
-struct AlbumInfo {
-    id: String,
-    total_tracks: usize,
-}
 
 pub async fn fetch_all_items<T: serde::de::DeserializeOwned>(
     client: &Client,
     url: &str,
-    auth_token: &str,
+    tokens: &TokenManager,
 ) -> Result<Vec<T>, Box<dyn Error>> {
     let mut all_items = Vec::new();
     let mut next_url = Some(url.to_string());
 
     while let Some(url) = next_url {
-        let response: Value = client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", auth_token))
-            .send()
-            .await?
-            .json()
-            .await?;
+        let response = get_json(client, &url, tokens).await?;
 
         all_items.extend(response["items"].as_array().unwrap().iter().cloned());
         next_url = response["next"].as_str().map(String::from);
@@ -235,9 +349,35 @@ pub fn get_client() -> reqwest::Client {
     headers.insert("sec-fetch-user", "?1".parse().unwrap());
     headers.insert("upgrade-insecure-requests", "1".parse().unwrap());
     headers.insert("user-agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36".parse().unwrap());
-    let client = reqwest::Client::builder()
+    reqwest::Client::builder()
         .default_headers(headers)
         .build()
-        .unwrap();
-    client
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_reads_seconds() {
+        assert_eq!(parse_retry_after(" 7 "), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn retry_after_reads_http_dates() {
+        let until = SystemTime::now() + Duration::from_secs(120);
+        let wait = parse_retry_after(&httpdate::fmt_http_date(until)).unwrap();
+        assert!(wait > Duration::from_secs(110) && wait <= Duration::from_secs(120));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn retry_after_rejects_garbage() {
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-3"), None);
+    }
 }
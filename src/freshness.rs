@@ -0,0 +1,201 @@
+use std::{error::Error, sync::Arc, time::Duration};
+
+use fred::prelude::*;
+use futures::StreamExt;
+
+use crate::clock::millis_from_now;
+use crate::env::env_or;
+use crate::processed::ProcessedArtists;
+use crate::task::Discovery;
+use crate::AppState;
+
+/// Artist id scored by when it was last crawled, in milliseconds since the epoch
+const LAST_CRAWLED: &str = "artist_last_crawled_at";
+/// Artist id scored by when it is due to be crawled again
+const RECRAWL_DUE: &str = "artist_recrawl_due_at";
+/// Set once `backfill` has run, so later startups skip it
+const BACKFILLED: &str = "artist_recrawl_backfilled";
+const BACKFILL_CHUNK: u32 = 1000;
+
+/// Re-crawl settings, read from the environment.
+#[derive(Debug, Clone)]
+pub struct FreshnessConfig {
+    /// `RECRAWL_TTL_SECS`, how old an artist's data may get before it is crawled
+    /// again. 0 disables re-crawls.
+    pub ttl: Duration,
+    /// `RECRAWL_HUB_TTL_SECS`, a shorter TTL for artists with at least
+    /// `hub_collaborators` collaborators. Defaults to `ttl`.
+    pub hub_ttl: Duration,
+    /// `RECRAWL_HUB_COLLABORATORS`
+    pub hub_collaborators: usize,
+    /// `RECRAWL_INTERVAL_SECS`, how often the scheduler looks for stale artists.
+    pub interval: Duration,
+    /// `RECRAWL_BATCH`, most artists queued per scheduler run.
+    pub batch: usize,
+}
+
+impl FreshnessConfig {
+    pub fn from_env() -> Self {
+        let ttl = env_or("RECRAWL_TTL_SECS", 30 * 24 * 3600);
+        FreshnessConfig {
+            ttl: Duration::from_secs(ttl),
+            hub_ttl: Duration::from_secs(env_or("RECRAWL_HUB_TTL_SECS", ttl)),
+            hub_collaborators: env_or("RECRAWL_HUB_COLLABORATORS", 50),
+            interval: Duration::from_secs(env_or("RECRAWL_INTERVAL_SECS", 3600)),
+            batch: env_or("RECRAWL_BATCH", 1000),
+        }
+    }
+
+    fn ttl_for(&self, collaborators: usize) -> Duration {
+        if collaborators >= self.hub_collaborators {
+            self.hub_ttl.min(self.ttl)
+        } else {
+            self.ttl
+        }
+    }
+}
+
+/// Records that `artist_id` was just crawled and schedules its next crawl.
+pub async fn record_crawl(
+    redis_client: &RedisClient,
+    config: &FreshnessConfig,
+    artist_id: &str,
+    collaborators: usize,
+) -> Result<(), Box<dyn Error>> {
+    let now = millis_from_now(Duration::ZERO);
+    redis_client
+        .zadd::<(), _, _>(
+            LAST_CRAWLED,
+            None,
+            None,
+            false,
+            false,
+            (now as f64, artist_id),
+        )
+        .await?;
+    if config.ttl.is_zero() {
+        return Ok(());
+    }
+    let due = millis_from_now(config.ttl_for(collaborators));
+    redis_client
+        .zadd::<(), _, _>(
+            RECRAWL_DUE,
+            None,
+            None,
+            false,
+            false,
+            (due as f64, artist_id),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Schedules artists crawled before crawl times were recorded as due now. The
+/// scheduler's batch size spreads them out. Runs once per Redis; every artist
+/// crawled since has its due time recorded by `record_crawl`.
+async fn backfill(redis_client: &RedisClient) -> Result<usize, Box<dyn Error>> {
+    let backfilled: bool = redis_client.exists(BACKFILLED).await?;
+    if backfilled {
+        return Ok(0);
+    }
+    let processed = ProcessedArtists::new(redis_client);
+    let mut pages = std::pin::pin!(processed.scan(BACKFILL_CHUNK));
+    let now = millis_from_now(Duration::ZERO) as f64;
+    let mut added = 0;
+    while let Some(page) = pages.next().await {
+        let page = page?;
+        if page.is_empty() {
+            continue;
+        }
+        let members: Vec<(f64, &str)> = page.iter().map(|id| (now, id.as_str())).collect();
+        let count: i64 = redis_client
+            .zadd(
+                RECRAWL_DUE,
+                Some(SetOptions::NX),
+                None,
+                false,
+                false,
+                members,
+            )
+            .await?;
+        added += count as usize;
+    }
+    redis_client
+        .set::<(), _, _>(BACKFILLED, 1, None, None, false)
+        .await?;
+    Ok(added)
+}
+
+/// Queues up to `config.batch` artists whose data is older than their TTL, at the
+/// depth they were crawled at. Their due time moves a TTL ahead so they are not
+/// queued again before the crawl records a new one.
+async fn schedule_stale(
+    state: &AppState,
+    config: &FreshnessConfig,
+) -> Result<usize, Box<dyn Error>> {
+    let now = millis_from_now(Duration::ZERO);
+    let stale: Vec<String> = state
+        .redis_client
+        .zrangebyscore(
+            RECRAWL_DUE,
+            "-inf",
+            now as f64,
+            false,
+            Some((0, config.batch as i64)),
+        )
+        .await?;
+    if stale.is_empty() {
+        return Ok(0);
+    }
+    let stale_ids: Vec<&str> = stale.iter().map(String::as_str).collect();
+    let depths = ProcessedArtists::new(&state.redis_client)
+        .depths(&stale_ids)
+        .await?;
+    let discoveries = stale
+        .iter()
+        .zip(depths)
+        .map(|(artist_id, depth)| Discovery {
+            artist_id: artist_id.clone(),
+            weight: 1,
+            depth: depth.unwrap_or(0),
+        })
+        .collect();
+    state.queue.enqueue(discoveries).await?;
+
+    let retry_at = millis_from_now(config.ttl) as f64;
+    let members: Vec<(f64, &str)> = stale.iter().map(|id| (retry_at, id.as_str())).collect();
+    state
+        .redis_client
+        .zadd::<(), _, _>(RECRAWL_DUE, None, None, false, false, members)
+        .await?;
+    Ok(stale.len())
+}
+
+/// Starts the scheduler re-queueing stale artists on the current runtime.
+pub(crate) fn start_recrawl_scheduler(state: Arc<AppState>) {
+    if state.freshness.ttl.is_zero() {
+        return;
+    }
+    ntex::rt::spawn(run_scheduler(state));
+}
+
+async fn run_scheduler(state: Arc<AppState>) {
+    let config = &state.freshness;
+    match backfill(&state.redis_client).await {
+        Ok(0) => {}
+        Ok(added) => println!(
+            "Scheduled {} previously crawled artists for a re-crawl",
+            added
+        ),
+        Err(e) => eprintln!("Failed to schedule previously crawled artists: {:?}", e),
+    }
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        match schedule_stale(&state, config).await {
+            Ok(0) => {}
+            Ok(queued) => println!("Queued {} stale artists for a re-crawl", queued),
+            Err(e) => eprintln!("Failed to queue stale artists: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, error::Error, time::Instant};
+
+use futures::StreamExt;
+use itertools::Itertools;
+use scylla::{query::Query, Session};
+use serde::Serialize;
+
+use crate::types::NormalizedTrack;
+
+const LOAD_PAGE_SIZE: i32 = 5000;
+
+/// A collaboration between two artists, identified by the index of the other artist.
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub neighbor: u32,
+    pub tracks: Vec<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GraphStats {
+    pub artists: usize,
+    pub tracks: usize,
+    pub collaborations: usize,
+    pub max_degree: usize,
+    pub average_degree: f64,
+}
+
+/// Spotify release dates are `YYYY`, `YYYY-MM` or `YYYY-MM-DD` depending on precision.
+fn release_year(release_date: &str) -> Option<u16> {
+    release_date.get(..4)?.parse().ok()
+}
+
+/// In-memory featured-artist graph built from `music.tracks`.
+///
+/// Artists and tracks are interned to `u32` indices; each artist keeps its
+/// collaborations sorted by neighbor index so edges can be looked up by binary search.
+#[derive(Debug, Default)]
+pub struct CollaborationGraph {
+    artist_ids: Vec<String>,
+    artist_index: HashMap<String, u32>,
+    track_ids: Vec<String>,
+    track_index: HashMap<String, u32>,
+    track_years: Vec<Option<u16>>,
+    adjacency: Vec<Vec<Edge>>,
+}
+
+impl CollaborationGraph {
+    pub async fn load(session: &Session) -> Result<Self, Box<dyn Error>> {
+        let before = Instant::now();
+        let query = Query::new("SELECT id, artists, release_date FROM music.tracks")
+            .with_page_size(LOAD_PAGE_SIZE);
+        let mut rows = session
+            .query_iter(query, ())
+            .await?
+            .into_typed::<(String, Vec<String>, Option<String>)>();
+
+        let mut graph = CollaborationGraph::default();
+        while let Some(row) = rows.next().await {
+            let (track_id, artists, release_date) = row?;
+            graph.add_track(&track_id, &artists, release_date.as_deref());
+        }
+
+        println!(
+            "Loaded collaboration graph with {} artists and {} tracks in {:?}",
+            graph.artist_count(),
+            graph.track_count(),
+            before.elapsed()
+        );
+        Ok(graph)
+    }
+
+    pub fn add_tracks(&mut self, tracks: &[NormalizedTrack]) {
+        for track in tracks {
+            self.add_track(&track.id, &track.artists, track.release_date.as_deref());
+        }
+    }
+
+    pub fn add_track(&mut self, track_id: &str, artists: &[String], release_date: Option<&str>) {
+        if artists.len() < 2 {
+            return;
+        }
+        let track = match self.track_index.get(track_id) {
+            Some(&index) => index,
+            None => {
+                let index = self.track_ids.len() as u32;
+                self.track_ids.push(track_id.to_string());
+                self.track_index.insert(track_id.to_string(), index);
+                self.track_years.push(release_date.and_then(release_year));
+                index
+            }
+        };
+        let artists: Vec<u32> = artists.iter().map(|id| self.intern_artist(id)).collect();
+        for (i, &a) in artists.iter().enumerate() {
+            for &b in &artists[i + 1..] {
+                if a != b {
+                    self.link(a, b, track);
+                    self.link(b, a, track);
+                }
+            }
+        }
+    }
+
+    fn intern_artist(&mut self, artist_id: &str) -> u32 {
+        if let Some(&index) = self.artist_index.get(artist_id) {
+            return index;
+        }
+        let index = self.artist_ids.len() as u32;
+        self.artist_ids.push(artist_id.to_string());
+        self.artist_index.insert(artist_id.to_string(), index);
+        self.adjacency.push(Vec::new());
+        index
+    }
+
+    fn link(&mut self, from: u32, to: u32, track: u32) {
+        let edges = &mut self.adjacency[from as usize];
+        match edges.binary_search_by_key(&to, |edge| edge.neighbor) {
+            Ok(position) => {
+                let tracks = &mut edges[position].tracks;
+                if !tracks.contains(&track) {
+                    tracks.push(track);
+                }
+            }
+            Err(position) => edges.insert(
+                position,
+                Edge {
+                    neighbor: to,
+                    tracks: vec![track],
+                },
+            ),
+        }
+    }
+
+    pub fn artist_index(&self, artist_id: &str) -> Option<u32> {
+        self.artist_index.get(artist_id).copied()
+    }
+
+    pub fn artist_id(&self, index: u32) -> &str {
+        &self.artist_ids[index as usize]
+    }
+
+    pub fn track_id(&self, index: u32) -> &str {
+        &self.track_ids[index as usize]
+    }
+
+    pub fn track_year(&self, index: u32) -> Option<u16> {
+        self.track_years[index as usize]
+    }
+
+    pub fn neighbors(&self, index: u32) -> &[Edge] {
+        &self.adjacency[index as usize]
+    }
+
+    /// Every collaboration track the artist appears on, sorted.
+    pub fn artist_tracks(&self, index: u32) -> Vec<u32> {
+        self.neighbors(index)
+            .iter()
+            .flat_map(|edge| edge.tracks.iter().copied())
+            .sorted_unstable()
+            .dedup()
+            .collect()
+    }
+
+    pub fn edge(&self, from: u32, to: u32) -> Option<&Edge> {
+        let edges = self.neighbors(from);
+        edges
+            .binary_search_by_key(&to, |edge| edge.neighbor)
+            .ok()
+            .map(|position| &edges[position])
+    }
+
+    pub fn artist_count(&self) -> usize {
+        self.artist_ids.len()
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.track_ids.len()
+    }
+
+    pub fn stats(&self) -> GraphStats {
+        let degrees = self.adjacency.iter().map(Vec::len);
+        let total_degree: usize = degrees.clone().sum();
+        GraphStats {
+            artists: self.artist_count(),
+            tracks: self.track_count(),
+            collaborations: total_degree / 2,
+            max_degree: degrees.max().unwrap_or(0),
+            average_degree: if self.artist_ids.is_empty() {
+                0.0
+            } else {
+                total_degree as f64 / self.artist_ids.len() as f64
+            },
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{error::Error, future::Future, time::Duration};
+
+use fred::prelude::*;
+use fred::types::RedisValue;
+use scylla::Session;
+
+use crate::clock::micros_now;
+use crate::env::env_or;
+use crate::task::applied;
+
+const LOCK_PREFIX: &str = "lock:artist";
+/// Artist id -> last fencing token handed out
+const FENCES: &str = "lock:artist:fences";
+const DEFAULT_LOCK_TTL_SECS: u64 = 30;
+
+/// Hands out the artist's next fencing token: the current time in microseconds, or
+/// one past the last token if the clock is behind it.
+const NEXT_TOKEN_SCRIPT: &str = r#"
+local last = tonumber(redis.call("HGET", KEYS[1], ARGV[1]) or "0")
+local token = math.max(last + 1, tonumber(ARGV[2]))
+redis.call("HSET", KEYS[1], ARGV[1], token)
+return token
+"#;
+
+/// Deletes the lock only if it still holds our token.
+const RELEASE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Extends the lock only if it still holds our token.
+const RENEW_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+/// `ARTIST_LOCK_TTL_SECS`, how long an artist lock outlives a holder that stops
+/// renewing it. Holders renew at a third of this.
+pub fn lock_ttl() -> Duration {
+    Duration::from_secs(env_or("ARTIST_LOCK_TTL_SECS", DEFAULT_LOCK_TTL_SECS))
+}
+
+/// Exclusive right to crawl one artist, held in Redis.
+///
+/// Every acquisition gets a fencing token that grows with each acquisition and
+/// doubles as the lock value. A holder that stalls past the TTL can no longer renew
+/// or release the lock. Its Scylla writes carry the token as their write time, so
+/// they lose to a newer holder's, and its Redis writes check the lock value in the
+/// same script, so they are dropped once the lock has moved on.
+pub struct ArtistLock {
+    client: RedisClient,
+    artist_id: String,
+    key: String,
+    /// Fencing token, also the write time of the holder's Scylla writes in microseconds
+    pub token: i64,
+}
+
+impl ArtistLock {
+    /// Takes the lock, or returns `None` if another holder has it.
+    pub async fn acquire(
+        client: &RedisClient,
+        artist_id: &str,
+        ttl: Duration,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        let token: i64 = client
+            .eval(
+                NEXT_TOKEN_SCRIPT,
+                vec![FENCES],
+                vec![RedisValue::from(artist_id), micros_now().into()],
+            )
+            .await?;
+        let key = format!("{}:{}", LOCK_PREFIX, artist_id);
+        let acquired: Option<String> = client
+            .set(
+                &key,
+                token,
+                Some(Expiration::PX(ttl.as_millis() as i64)),
+                Some(SetOptions::NX),
+                false,
+            )
+            .await?;
+        if acquired.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(ArtistLock {
+            client: client.clone(),
+            artist_id: artist_id.to_string(),
+            key,
+            token,
+        }))
+    }
+
+    /// Pushes the expiry `ttl` ahead. Returns false if the lock was lost.
+    pub async fn renew(&self, ttl: Duration) -> Result<bool, Box<dyn Error>> {
+        let renewed: i64 = self
+            .client
+            .eval(
+                RENEW_SCRIPT,
+                vec![self.key.as_str()],
+                vec![self.token, ttl.as_millis() as i64],
+            )
+            .await?;
+        Ok(renewed == 1)
+    }
+
+    /// Runs `work` while renewing the lock every third of `ttl`. Gives up on `work`
+    /// with an error as soon as the lock is lost.
+    pub async fn while_held<T>(
+        &self,
+        ttl: Duration,
+        work: impl Future<Output = Result<T, Box<dyn Error>>>,
+    ) -> Result<T, Box<dyn Error>> {
+        tokio::pin!(work);
+        let mut renewal = tokio::time::interval((ttl / 3).max(Duration::from_secs(1)));
+        renewal.tick().await;
+        loop {
+            tokio::select! {
+                result = &mut work => return result,
+                _ = renewal.tick() => {
+                    if !self.renew(ttl).await? {
+                        return Err(format!("Lost the lock on artist {}", self.artist_id).into());
+                    }
+                }
+            }
+        }
+    }
+
+    /// The lock's Redis key, which holds `token` for as long as the lock is ours.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Whether the lock still holds our token.
+    pub async fn held(&self) -> Result<bool, Box<dyn Error>> {
+        let value: Option<i64> = self.client.get(&self.key).await?;
+        Ok(value == Some(self.token))
+    }
+
+    /// Releases the lock if it is still ours. Returns false if it had been lost.
+    pub async fn release(self) -> Result<bool, Box<dyn Error>> {
+        let released: i64 = self
+            .client
+            .eval(RELEASE_SCRIPT, vec![self.key.as_str()], vec![self.token])
+            .await?;
+        Ok(released == 1)
+    }
+
+    /// Claims the artist's rows in `music.artist_fences` for this token. Returns
+    /// false if a holder with a newer token already wrote, in which case this holder
+    /// must not write.
+    pub async fn fence(&self, session: &Session) -> Result<bool, Box<dyn Error>> {
+        let result = session
+            .query(
+                "INSERT INTO music.artist_fences (artist_id, token) VALUES (?, ?) IF NOT EXISTS",
+                (&self.artist_id, self.token),
+            )
+            .await?;
+        if applied(result) {
+            return Ok(true);
+        }
+        let result = session
+            .query(
+                "UPDATE music.artist_fences SET token = ? WHERE artist_id = ? IF token <= ?",
+                (self.token, &self.artist_id, self.token),
+            )
+            .await?;
+        Ok(applied(result))
+    }
+}
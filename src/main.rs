@@ -1,29 +1,60 @@
 use fred::prelude::*;
 use ntex::web;
-use reqwest;
 use scylla::{statement::Consistency, ExecutionProfile, Session, SessionBuilder};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::RwLock;
 
 pub mod batch;
+pub mod clock;
+pub mod concurrency;
 pub mod db;
+pub mod disjoint;
+pub mod env;
 pub mod etl;
 pub mod fetch;
+pub mod freshness;
+pub mod graph;
+pub mod lock;
 pub mod parquet;
+pub mod path;
+pub mod processed;
+pub mod queue;
+pub mod rate_limit;
+pub mod redis_queue;
+pub mod retry;
+pub mod search;
 pub mod task;
+pub mod token;
 pub mod types;
+pub mod weighted;
+pub mod worker;
 
+use concurrency::{concurrency_limiter, ConcurrencyMetrics};
 use db::setup_keyspace;
-use etl::process_artist;
-use fetch::{get_api_key, get_client};
-use task::{complete_task, enqueue_tasks, setup_task_table};
+use etl::{process_artist, CrawlOutcome};
+use freshness::{record_crawl, start_recrawl_scheduler, FreshnessConfig};
+use graph::{CollaborationGraph, GraphStats};
+use path::{
+    find_paths, resolve_details, ArtistPath, PathConstraints, PathMode, PathOptions, PathOutcome,
+    SearchLimits,
+};
+use queue::{queue_from_env, TaskQueue};
+use rate_limit::init_rate_limiter;
+use search::{ArtistIndex, NameCandidate, SearchHit, DEFAULT_SEARCH_LIMIT, MAX_SEARCH_LIMIT};
+use task::{DeadLetter, Discovery};
+use token::{token_provider_from_env, TokenManager};
+use worker::{start_workers, WorkerConfig};
 
 struct AppState {
     session: Arc<Session>,
     redis_client: RedisClient,
     http_client: reqwest::Client,
-    auth_token: Mutex<String>,
+    tokens: Arc<TokenManager>,
+    graph: RwLock<CollaborationGraph>,
+    artist_index: RwLock<ArtistIndex>,
+    queue: Arc<dyn TaskQueue>,
+    freshness: FreshnessConfig,
 }
 
 #[derive(Deserialize)]
@@ -31,48 +62,172 @@ struct ArtistIds {
     ids: Vec<String>,
 }
 
+#[derive(Deserialize)]
+struct PathQuery {
+    /// Artist id or name
+    from: String,
+    /// Artist id or name
+    to: String,
+    #[serde(default)]
+    mode: PathMode,
+    k: Option<usize>,
+    max_hops: Option<usize>,
+    max_expansions: Option<usize>,
+    /// Comma-separated artist ids or names
+    exclude: Option<String>,
+    /// Comma-separated artist ids or names
+    require: Option<String>,
+    from_year: Option<u16>,
+    to_year: Option<u16>,
+    recency_half_life: Option<f64>,
+}
+
+fn split_ids(ids: &Option<String>) -> Vec<String> {
+    ids.as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// JSON error response shared by the endpoints.
+#[derive(Serialize)]
+struct ErrorBody {
+    reason: String,
+    /// Search expansions spent before a path search gave up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expansions: Option<usize>,
+}
+
+impl ErrorBody {
+    fn new(reason: String) -> Self {
+        ErrorBody {
+            reason,
+            expansions: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AmbiguousArtist {
+    reason: String,
+    candidates: Vec<NameCandidate>,
+}
+
+#[derive(Serialize)]
+struct PathsResult {
+    paths: Vec<ArtistPath>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incomplete: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Neighbor {
+    id: String,
+    tracks: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct NeighborsResult {
+    id: String,
+    neighbors: Vec<Neighbor>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    artists: Vec<SearchHit>,
+}
+
+#[derive(Deserialize)]
+struct LookupQuery {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct LookupResult {
+    candidates: Vec<NameCandidate>,
+}
+
+#[derive(Deserialize)]
+struct DeadLetterQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DeadLettersResult {
+    tasks: Vec<DeadLetter>,
+}
+
+#[derive(Serialize)]
+struct RequeueResult {
+    requeued: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Metrics {
+    spotify_concurrency: ConcurrencyMetrics,
+}
+
 #[derive(Serialize)]
 struct ProcessingResult {
     successful: Vec<String>,
     failed: Vec<String>,
+    /// Artists another instance was already crawling
+    skipped: Vec<String>,
 }
+/// Crawls one artist and adds what it turned up to the in-memory graph and index.
+/// Leaves the artist's task, if it has one, alone.
+/// Returns false, having done nothing, if another instance holds the artist's lock.
 async fn process_single_artist(
-    state: &web::types::State<Arc<AppState>>,
+    state: &Arc<AppState>,
     artist_id: &str,
-    retry_count: &mut i32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    loop {
-        let auth_token = state.auth_token.lock().await.clone();
-        match process_artist(
-            artist_id,
-            &state.redis_client,
-            &state.session,
-            &auth_token,
-            &state.http_client,
-        )
-        .await
-        {
-            Ok(_) => {
-                if let Err(e) = complete_task(&state.session, artist_id).await {
-                    return Err(e.into());
-                }
-                return Ok(());
-            }
-            Err(e) => {
-                if *retry_count == 0 {
-                    let _ = refresh_token(state.get_ref().clone()).await;
-                    *retry_count += 1;
-                } else {
-                    if let Err(enqueue_err) =
-                        enqueue_tasks(&state.session, vec![artist_id.to_string()]).await
-                    {
-                        eprintln!("Error re-enqueueing task: {:?}", enqueue_err);
-                    }
-                    return Err(e);
-                }
-            }
-        }
+    depth: i32,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let processed = match process_artist(
+        artist_id,
+        depth,
+        &state.redis_client,
+        &state.session,
+        state.queue.as_ref(),
+        &state.tokens,
+        &state.http_client,
+    )
+    .await?
+    {
+        CrawlOutcome::Crawled(processed) => processed,
+        CrawlOutcome::Locked => return Ok(false),
+    };
+    let collaborators = {
+        let mut graph = state.graph.write().await;
+        graph.add_tracks(&processed.tracks);
+        graph
+            .artist_index(artist_id)
+            .map_or(0, |index| graph.neighbors(index).len())
+    };
+    if let Err(e) = record_crawl(
+        &state.redis_client,
+        &state.freshness,
+        artist_id,
+        collaborators,
+    )
+    .await
+    {
+        eprintln!("Failed to record crawl of artist {}: {:?}", artist_id, e);
     }
+    state
+        .artist_index
+        .write()
+        .await
+        .add_artists(&processed.artists);
+    Ok(true)
 }
 
 #[web::post("/process_artists")]
@@ -82,24 +237,237 @@ async fn process_artists(
 ) -> Result<web::HttpResponse, web::Error> {
     let mut successful = Vec::new();
     let mut failed = Vec::new();
-    let mut retry_count = 0;
+    let mut skipped = Vec::new();
 
     for artist_id in artist_ids.into_inner().ids.iter() {
-        let result = process_single_artist(&state, artist_id, &mut retry_count).await;
+        // Artists posted here are seeds of the crawl
+        let result = process_single_artist(&state, artist_id, 0).await;
+        if result.is_err() {
+            // Hand the artist to the workers to retry
+            let retry = Discovery {
+                artist_id: artist_id.clone(),
+                weight: 1,
+                depth: 0,
+            };
+            if let Err(e) = state.queue.enqueue(vec![retry]).await {
+                eprintln!("Error queueing artist {} for retry: {:?}", artist_id, e);
+            }
+        }
         match result {
-            Ok(_) => successful.push(artist_id.clone()),
+            Ok(true) => successful.push(artist_id.clone()),
+            Ok(false) => skipped.push(artist_id.clone()),
             Err(_) => failed.push(artist_id.clone()),
         }
     }
 
-    Ok(ntex::web::HttpResponse::Ok().json(&ProcessingResult { successful, failed }))
+    Ok(ntex::web::HttpResponse::Ok().json(&ProcessingResult {
+        successful,
+        failed,
+        skipped,
+    }))
+}
+#[web::get("/tasks/dead_letters")]
+async fn dead_letters(
+    state: web::types::State<Arc<AppState>>,
+    query: web::types::Query<DeadLetterQuery>,
+) -> web::HttpResponse {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    match state.queue.dead_letters(limit).await {
+        Ok(tasks) => web::HttpResponse::Ok().json(&DeadLettersResult { tasks }),
+        Err(e) => {
+            eprintln!("Error listing dead letters: {:?}", e);
+            web::HttpResponse::InternalServerError().json(&ErrorBody::new(e.to_string()))
+        }
+    }
+}
+
+#[web::post("/tasks/dead_letters/requeue")]
+async fn requeue_dead_letter_tasks(
+    state: web::types::State<Arc<AppState>>,
+    artist_ids: web::types::Json<ArtistIds>,
+) -> web::HttpResponse {
+    match state.queue.requeue_dead_letters(&artist_ids.ids).await {
+        Ok(requeued) => web::HttpResponse::Ok().json(&RequeueResult { requeued }),
+        Err(e) => {
+            eprintln!("Error requeueing dead letters: {:?}", e);
+            web::HttpResponse::InternalServerError().json(&ErrorBody::new(e.to_string()))
+        }
+    }
+}
+
+#[web::get("/path")]
+async fn shortest_path(
+    state: web::types::State<Arc<AppState>>,
+    query: web::types::Query<PathQuery>,
+) -> web::HttpResponse {
+    let disjoint = matches!(query.mode, PathMode::NodeDisjoint | PathMode::EdgeDisjoint);
+    if disjoint && query.max_hops.is_some() {
+        return web::HttpResponse::BadRequest().json(&ErrorBody::new(
+            "max_hops does not apply to disjoint modes".to_string(),
+        ));
+    }
+    let defaults = SearchLimits::default();
+    let limits = SearchLimits {
+        max_hops: query.max_hops.unwrap_or(defaults.max_hops),
+        max_expansions: query.max_expansions.unwrap_or(defaults.max_expansions),
+    };
+    let graph = state.graph.read().await;
+    let artist_index = state.artist_index.read().await;
+    let resolve = |value: &str| {
+        artist_index.resolve(value, &graph).map_err(|candidates| {
+            web::HttpResponse::BadRequest().json(&AmbiguousArtist {
+                reason: format!("Several artists are named {}", value),
+                candidates,
+            })
+        })
+    };
+    let resolve_all = |values: Vec<String>| {
+        values
+            .iter()
+            .map(|value| resolve(value))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let (from, to, exclude, require) = match (
+        resolve(&query.from),
+        resolve(&query.to),
+        resolve_all(split_ids(&query.exclude)),
+        resolve_all(split_ids(&query.require)),
+    ) {
+        (Ok(from), Ok(to), Ok(exclude), Ok(require)) => (from, to, exclude, require),
+        (Err(response), ..) | (_, Err(response), ..) => return response,
+        (.., Err(response), _) | (.., Err(response)) => return response,
+    };
+    drop(artist_index);
+    let options = PathOptions {
+        mode: query.mode,
+        k: query.k,
+        limits,
+        constraints: PathConstraints {
+            exclude,
+            require,
+            from_year: query.from_year,
+            to_year: query.to_year,
+        },
+        recency_half_life: query.recency_half_life,
+    };
+    let outcome = find_paths(&graph, &from, &to, &options);
+    drop(graph);
+    let (mut paths, incomplete) = match outcome {
+        PathOutcome::Found(paths) => (paths, None),
+        PathOutcome::Partial { paths, reason } => (paths, Some(reason)),
+        PathOutcome::NotFound(reason) => {
+            return web::HttpResponse::NotFound().json(&ErrorBody::new(reason))
+        }
+        PathOutcome::Invalid(reason) => {
+            return web::HttpResponse::BadRequest().json(&ErrorBody::new(reason))
+        }
+        PathOutcome::GaveUp { expansions, reason } => {
+            return web::HttpResponse::NotFound().json(&ErrorBody {
+                reason,
+                expansions: Some(expansions),
+            })
+        }
+    };
+    match resolve_details(&state.session, &mut paths).await {
+        Ok(_) => web::HttpResponse::Ok().json(&PathsResult { paths, incomplete }),
+        Err(e) => {
+            eprintln!("Error resolving path details: {:?}", e);
+            web::HttpResponse::InternalServerError().json(&ErrorBody::new(e.to_string()))
+        }
+    }
+}
+
+#[web::get("/artists/{id}/neighbors")]
+async fn neighbors(
+    state: web::types::State<Arc<AppState>>,
+    artist_id: web::types::Path<String>,
+) -> web::HttpResponse {
+    let graph = state.graph.read().await;
+    let Some(artist) = graph.artist_index(&artist_id) else {
+        return web::HttpResponse::NotFound()
+            .json(&ErrorBody::new(format!("Unknown artist {}", artist_id)));
+    };
+    let neighbors = graph
+        .neighbors(artist)
+        .iter()
+        .map(|edge| Neighbor {
+            id: graph.artist_id(edge.neighbor).to_string(),
+            tracks: edge
+                .tracks
+                .iter()
+                .map(|&track| graph.track_id(track).to_string())
+                .collect(),
+        })
+        .collect();
+    web::HttpResponse::Ok().json(&NeighborsResult {
+        id: artist_id.into_inner(),
+        neighbors,
+    })
+}
+
+#[web::get("/artists/search")]
+async fn search_artists(
+    state: web::types::State<Arc<AppState>>,
+    query: web::types::Query<SearchQuery>,
+) -> web::HttpResponse {
+    if query.q.trim().is_empty() {
+        return web::HttpResponse::BadRequest()
+            .json(&ErrorBody::new("Query must not be empty".to_string()));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let graph = state.graph.read().await;
+    let artists = state
+        .artist_index
+        .read()
+        .await
+        .search(&query.q, limit, &graph);
+    web::HttpResponse::Ok().json(&SearchResult { artists })
+}
+
+#[web::get("/artists/lookup")]
+async fn lookup_artist(
+    state: web::types::State<Arc<AppState>>,
+    query: web::types::Query<LookupQuery>,
+) -> web::HttpResponse {
+    let graph = state.graph.read().await;
+    let candidates = state
+        .artist_index
+        .read()
+        .await
+        .candidates(&query.name, &graph);
+    if candidates.is_empty() {
+        return web::HttpResponse::NotFound()
+            .json(&ErrorBody::new(format!("No artist named {}", query.name)));
+    }
+    web::HttpResponse::Ok().json(&LookupResult { candidates })
+}
+
+#[web::get("/graph/stats")]
+async fn graph_stats(state: web::types::State<Arc<AppState>>) -> web::HttpResponse {
+    let stats: GraphStats = state.graph.read().await.stats();
+    web::HttpResponse::Ok().json(&stats)
 }
+
+#[web::get("/metrics")]
+async fn metrics(_: web::types::State<Arc<AppState>>) -> web::HttpResponse {
+    web::HttpResponse::Ok().json(&Metrics {
+        spotify_concurrency: concurrency_limiter().metrics(),
+    })
+}
+
 #[web::get("/health")]
 async fn health(_: web::types::State<Arc<AppState>>) -> web::HttpResponse {
     web::HttpResponse::Ok().body("OK")
 }
 #[ntex::main]
 async fn main() -> std::io::Result<()> {
+    match task::init_max_depth().expect("Invalid CRAWL_MAX_DEPTH") {
+        Some(depth) => println!("Crawling up to {} hops from the seed artists", depth),
+        None => println!("Crawling without a depth limit"),
+    }
     let uri = std::env::var("SCYLLA_URI").unwrap();
     let profile = ExecutionProfile::builder()
         .consistency(Consistency::One)
@@ -124,48 +492,56 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to connect to Redis");
     println!("Connected to Redis");
-    let http_client = get_client();
-    let initial_token = get_api_key(&http_client)
+    init_rate_limiter(redis_client.clone());
+    let http_client = reqwest::Client::new();
+    let token_provider =
+        token_provider_from_env(http_client.clone()).expect("Failed to setup token provider");
+    let tokens = TokenManager::start(token_provider)
         .await
         .expect("Failed to get initial API key");
     println!("Got initial token");
     setup_keyspace(&session)
         .await
         .expect("Failed to setup keyspace");
-    setup_task_table(&session)
+    println!("Setup keyspace");
+    let session = Arc::new(session);
+    let queue = queue_from_env(session.clone(), redis_client.clone())
+        .await
+        .expect("Failed to setup task queue");
+    let graph = CollaborationGraph::load(&session)
+        .await
+        .expect("Failed to load collaboration graph");
+    let artist_index = ArtistIndex::load(&session)
         .await
-        .expect("Failed to setup task table");
-    println!("Setup keyspace and task table");
+        .expect("Failed to load artist search index");
     let state = Arc::new(AppState {
-        session: Arc::new(session),
+        session,
         redis_client,
         http_client,
-        auth_token: Mutex::new(initial_token),
+        tokens,
+        graph: RwLock::new(graph),
+        artist_index: RwLock::new(artist_index),
+        queue,
+        freshness: FreshnessConfig::from_env(),
     });
+    start_workers(state.clone(), WorkerConfig::from_env());
+    start_recrawl_scheduler(state.clone());
 
     web::HttpServer::new(move || {
         web::App::new()
             .state(state.clone())
             .service(process_artists)
+            .service(dead_letters)
+            .service(requeue_dead_letter_tasks)
+            .service(shortest_path)
+            .service(search_artists)
+            .service(lookup_artist)
+            .service(neighbors)
+            .service(graph_stats)
+            .service(metrics)
             .service(health)
     })
     .bind(("127.0.0.1", 3000))?
     .run()
     .await
 }
-
-async fn refresh_token(state: Arc<AppState>) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(3500));
-    loop {
-        interval.tick().await;
-        match get_api_key(&state.http_client).await {
-            Ok(new_token) => {
-                let mut token = state.auth_token.lock().await;
-                *token = new_token;
-            }
-            Err(e) => {
-                eprintln!("Failed to refresh token: {:?}", e);
-            }
-        }
-    }
-}
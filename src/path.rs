@@ -0,0 +1,1128 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    error::Error,
+    ops::RangeInclusive,
+};
+
+use itertools::Itertools;
+
+use scylla::Session;
+use serde::{Deserialize, Serialize};
+
+use crate::disjoint::{edge_disjoint_paths, node_disjoint_paths, DisjointPaths};
+use crate::graph::{CollaborationGraph, Edge};
+use crate::types::Artist;
+use crate::weighted::{lightest_path, CollaborationWeights};
+
+const DEFAULT_MAX_HOPS: usize = 6;
+const DEFAULT_MAX_EXPANSIONS: usize = 250_000;
+// Searches run on the request thread while holding the graph read lock, so callers
+// may not raise the limits past these
+const MAX_HOPS: usize = 12;
+const MAX_EXPANSIONS: usize = 2_000_000;
+const MAX_K: usize = 10;
+const MAX_DISJOINT_PATHS: usize = 100;
+const MAX_PERMUTED_REQUIRED: usize = 4;
+const MAX_SEGMENT_ALTERNATIVES: usize = 5;
+const MAX_TRACKS_PER_HOP: usize = 5;
+// Scylla rejects IN restrictions on more than 100 partition keys by default
+const IN_QUERY_CHUNK_SIZE: usize = 100;
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct PathTrack {
+    pub id: String,
+    pub name: String,
+    pub preview_url: Option<String>,
+    pub album_name: Option<String>,
+    pub album_image: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Hop {
+    pub from: String,
+    pub to: String,
+    /// Tracks linking the two artists, at most `MAX_TRACKS_PER_HOP`.
+    pub tracks: Vec<PathTrack>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtistPath {
+    pub artists: Vec<Artist>,
+    pub hops: Vec<Hop>,
+    /// Sum of collaboration weights, only set in weighted mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum PathOutcome {
+    Found(Vec<ArtistPath>),
+    /// Some paths were found but the search stopped before it could finish.
+    Partial {
+        paths: Vec<ArtistPath>,
+        reason: String,
+    },
+    NotFound(String),
+    /// The request itself is contradictory or unsupported.
+    Invalid(String),
+    GaveUp {
+        expansions: usize,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PathMode {
+    /// The `k` shortest loopless chains.
+    #[default]
+    Shortest,
+    /// A maximum set of chains sharing no intermediate artist, of any length.
+    NodeDisjoint,
+    /// A maximum set of chains sharing no collaboration, i.e. no pair of artists, of
+    /// any length.
+    /// A track with three or more artists links several pairs, so two chains may
+    /// still hop over the same track through different pairs.
+    EdgeDisjoint,
+    /// The `k` lightest chains, where artists sharing more tracks are closer.
+    Weighted,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SearchLimits {
+    pub max_hops: usize,
+    pub max_expansions: usize,
+}
+
+impl SearchLimits {
+    /// The limits capped to what the server allows.
+    fn clamped(self) -> Self {
+        SearchLimits {
+            max_hops: self.max_hops.min(MAX_HOPS),
+            max_expansions: self.max_expansions.min(MAX_EXPANSIONS),
+        }
+    }
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        SearchLimits {
+            max_hops: DEFAULT_MAX_HOPS,
+            max_expansions: DEFAULT_MAX_EXPANSIONS,
+        }
+    }
+}
+
+/// Artists and collaborations a search may not use, plus the release-year window
+/// a collaboration needs at least one linking track in. Edges are stored with the
+/// smaller artist index first since the graph is undirected.
+#[derive(Debug, Default, Clone)]
+pub struct Exclusions {
+    artists: HashSet<u32>,
+    edges: HashSet<(u32, u32)>,
+    years: Option<RangeInclusive<u16>>,
+}
+
+impl Exclusions {
+    fn exclude_edge(&mut self, a: u32, b: u32) {
+        self.edges.insert((a.min(b), a.max(b)));
+    }
+
+    /// Tracks of `edge` released inside the year window.
+    pub fn linking_tracks<'a>(
+        &'a self,
+        graph: &'a CollaborationGraph,
+        edge: &'a Edge,
+    ) -> impl Iterator<Item = u32> + 'a {
+        edge.tracks
+            .iter()
+            .copied()
+            .filter(move |&track| match &self.years {
+                None => true,
+                Some(years) => graph
+                    .track_year(track)
+                    .is_some_and(|year| years.contains(&year)),
+            })
+    }
+
+    fn linking_track(&self, graph: &CollaborationGraph, edge: &Edge) -> Option<u32> {
+        self.linking_tracks(graph, edge).next()
+    }
+
+    pub fn allows(&self, graph: &CollaborationGraph, from: u32, edge: &Edge) -> bool {
+        let to = edge.neighbor;
+        !self.artists.contains(&to)
+            && !self.edges.contains(&(from.min(to), from.max(to)))
+            && self.linking_track(graph, edge).is_some()
+    }
+}
+
+/// Expansion budget shared by every search run while answering one request.
+pub struct Budget {
+    used: usize,
+    max: usize,
+}
+
+impl Budget {
+    pub fn new(max: usize) -> Self {
+        Budget { used: 0, max }
+    }
+
+    pub fn spend(&mut self) -> bool {
+        self.used += 1;
+        self.used <= self.max
+    }
+}
+
+pub enum Search {
+    Found(Vec<u32>),
+    Exhausted,
+    GaveUp,
+}
+
+/// One half of a bidirectional search: every visited artist maps to its parent
+/// (the root maps to itself) and its distance from the root.
+struct SearchSide {
+    visited: HashMap<u32, (u32, usize)>,
+    frontier: Vec<u32>,
+    depth: usize,
+}
+
+impl SearchSide {
+    fn new(root: u32) -> Self {
+        SearchSide {
+            visited: HashMap::from([(root, (root, 0))]),
+            frontier: vec![root],
+            depth: 0,
+        }
+    }
+
+    /// Artists from the root of this side down to `artist`.
+    fn chain_to(&self, artist: u32) -> Vec<u32> {
+        let mut chain = vec![artist];
+        let mut current = artist;
+        while let Some(&(parent, _)) = self.visited.get(&current) {
+            if parent == current {
+                break;
+            }
+            chain.push(parent);
+            current = parent;
+        }
+        chain.reverse();
+        chain
+    }
+}
+
+/// Bidirectional breadth-first search over the in-memory collaboration graph.
+///
+/// Each round expands one full level of whichever side has the smaller frontier.
+/// The search stops once the two sides together cover `max_hops`, or gives up once
+/// the budget is spent.
+fn bidirectional_search(
+    graph: &CollaborationGraph,
+    source: u32,
+    target: u32,
+    max_hops: usize,
+    exclusions: &Exclusions,
+    budget: &mut Budget,
+) -> Search {
+    if source == target {
+        return Search::Found(vec![source]);
+    }
+
+    let mut forward = SearchSide::new(source);
+    let mut backward = SearchSide::new(target);
+
+    while !forward.frontier.is_empty()
+        && !backward.frontier.is_empty()
+        && forward.depth + backward.depth < max_hops
+    {
+        let expand_forward = forward.frontier.len() <= backward.frontier.len();
+        let (side, other) = if expand_forward {
+            (&mut forward, &backward)
+        } else {
+            (&mut backward, &forward)
+        };
+
+        // Meeting edge (artist on this side, artist on the other side) with the shortest total length
+        let mut meeting: Option<(usize, u32, u32)> = None;
+        let mut next_frontier = Vec::new();
+        for &artist in &side.frontier {
+            if !budget.spend() {
+                return Search::GaveUp;
+            }
+            for edge in graph.neighbors(artist) {
+                if !exclusions.allows(graph, artist, edge) {
+                    continue;
+                }
+                if let Some(&(_, depth)) = other.visited.get(&edge.neighbor) {
+                    let total = side.depth + 1 + depth;
+                    if meeting.is_none_or(|(best, _, _)| total < best) {
+                        meeting = Some((total, artist, edge.neighbor));
+                    }
+                }
+                if let Entry::Vacant(entry) = side.visited.entry(edge.neighbor) {
+                    entry.insert((artist, side.depth + 1));
+                    next_frontier.push(edge.neighbor);
+                }
+            }
+        }
+        side.frontier = next_frontier;
+        side.depth += 1;
+
+        if let Some((_, near, far)) = meeting {
+            let (forward_end, backward_end) = if expand_forward {
+                (near, far)
+            } else {
+                (far, near)
+            };
+            let mut chain = forward.chain_to(forward_end);
+            chain.extend(backward.chain_to(backward_end).into_iter().rev());
+            return Search::Found(chain);
+        }
+    }
+
+    Search::Exhausted
+}
+
+/// Runs single-path searches ranked either by hop count or by collaboration weight.
+struct Searcher<'a> {
+    graph: &'a CollaborationGraph,
+    weights: Option<CollaborationWeights>,
+}
+
+impl Searcher<'_> {
+    fn search(
+        &self,
+        source: u32,
+        target: u32,
+        max_hops: usize,
+        exclusions: &Exclusions,
+        budget: &mut Budget,
+    ) -> Search {
+        match &self.weights {
+            None => bidirectional_search(self.graph, source, target, max_hops, exclusions, budget),
+            Some(weights) => lightest_path(
+                self.graph, source, target, max_hops, weights, exclusions, budget,
+            ),
+        }
+    }
+
+    fn cost(&self, chain: &[u32], exclusions: &Exclusions) -> f64 {
+        match &self.weights {
+            None => (chain.len() - 1) as f64,
+            Some(weights) => weights.path_weight(self.graph, chain, exclusions),
+        }
+    }
+}
+
+/// Yen's algorithm: every further path deviates from a previously found one at a
+/// "spur" artist, with the already-used continuations and the root prefix excluded.
+/// Stops early, keeping the paths found so far, once the budget is spent; the flag
+/// is true when that happened.
+fn k_shortest_paths(
+    searcher: &Searcher,
+    shortest: Vec<u32>,
+    k: usize,
+    max_hops: usize,
+    base: &Exclusions,
+    budget: &mut Budget,
+) -> (Vec<Vec<u32>>, bool) {
+    let target = *shortest.last().unwrap();
+    let mut found = vec![shortest];
+    let mut candidates: Vec<Vec<u32>> = Vec::new();
+
+    while found.len() < k {
+        let previous = found.last().unwrap().clone();
+        for spur_index in 0..previous.len() - 1 {
+            let root = &previous[..=spur_index];
+            let mut exclusions = base.clone();
+            for path in &found {
+                if path.len() > spur_index + 1 && &path[..=spur_index] == root {
+                    exclusions.exclude_edge(path[spur_index], path[spur_index + 1]);
+                }
+            }
+            exclusions.artists.extend(&root[..spur_index]);
+
+            match searcher.search(
+                previous[spur_index],
+                target,
+                max_hops - spur_index,
+                &exclusions,
+                budget,
+            ) {
+                Search::Found(spur) => {
+                    let mut candidate = root[..spur_index].to_vec();
+                    candidate.extend(spur);
+                    if !found.contains(&candidate) && !candidates.contains(&candidate) {
+                        candidates.push(candidate);
+                    }
+                }
+                Search::Exhausted => {}
+                Search::GaveUp => return (found, true),
+            }
+        }
+
+        let Some(shortest) = candidates
+            .iter()
+            .map(|candidate| searcher.cost(candidate, base))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(position, _)| position)
+        else {
+            break;
+        };
+        found.push(candidates.swap_remove(shortest));
+    }
+
+    (found, false)
+}
+
+/// A chain visiting `waypoints` in order, searched segment by segment. Each segment
+/// avoids the artists already on the chain and the waypoints still ahead.
+///
+/// Segments take their shortest route first. When that leaves a later segment with
+/// no route, say because it used the only artist leading on from the next waypoint,
+/// the segment's next `MAX_SEGMENT_ALTERNATIVES` routes are tried in turn. The first
+/// chain that connects is returned, so it need not be the shortest one overall.
+fn search_via(
+    searcher: &Searcher,
+    waypoints: &[u32],
+    max_hops: usize,
+    base: &Exclusions,
+    budget: &mut Budget,
+) -> Search {
+    extend_via(
+        searcher,
+        waypoints,
+        vec![waypoints[0]],
+        max_hops,
+        base,
+        budget,
+    )
+}
+
+/// Extends `chain`, which ends at `waypoints[0]`, through the rest of `waypoints`.
+fn extend_via(
+    searcher: &Searcher,
+    waypoints: &[u32],
+    chain: Vec<u32>,
+    max_hops: usize,
+    base: &Exclusions,
+    budget: &mut Budget,
+) -> Search {
+    let [from, to, ahead @ ..] = waypoints else {
+        return Search::Found(chain);
+    };
+    let Some(hops) = max_hops.checked_sub(chain.len() - 1 + ahead.len()) else {
+        return Search::Exhausted;
+    };
+    let mut exclusions = base.clone();
+    exclusions.artists.extend(&chain[..chain.len() - 1]);
+    exclusions.artists.extend(ahead);
+    let shortest = match searcher.search(*from, *to, hops, &exclusions, budget) {
+        Search::Found(path) => path,
+        other => return other,
+    };
+    let (routes, stopped_early) = if ahead.is_empty() {
+        (vec![shortest], false)
+    } else {
+        k_shortest_paths(
+            searcher,
+            shortest,
+            MAX_SEGMENT_ALTERNATIVES,
+            hops,
+            &exclusions,
+            budget,
+        )
+    };
+    for route in routes {
+        let mut extended = chain.clone();
+        extended.extend(&route[1..]);
+        match extend_via(searcher, &waypoints[1..], extended, max_hops, base, budget) {
+            Search::Exhausted => {}
+            other => return other,
+        }
+    }
+    if stopped_early {
+        Search::GaveUp
+    } else {
+        Search::Exhausted
+    }
+}
+
+/// Best chain for every visiting order of the required artists (or just the given
+/// order when there are too many to permute), cheapest first.
+fn required_paths(
+    searcher: &Searcher,
+    source: u32,
+    target: u32,
+    required: &[u32],
+    max_hops: usize,
+    base: &Exclusions,
+    budget: &mut Budget,
+) -> (Vec<Vec<u32>>, bool) {
+    let orders: Vec<Vec<u32>> = if required.len() <= MAX_PERMUTED_REQUIRED {
+        required
+            .iter()
+            .copied()
+            .permutations(required.len())
+            .collect()
+    } else {
+        vec![required.to_vec()]
+    };
+
+    let mut chains: Vec<Vec<u32>> = Vec::new();
+    let mut gave_up = false;
+    for order in orders {
+        let waypoints: Vec<u32> = std::iter::once(source)
+            .chain(order)
+            .chain(std::iter::once(target))
+            .collect();
+        match search_via(searcher, &waypoints, max_hops, base, budget) {
+            Search::Found(chain) if !chains.contains(&chain) => chains.push(chain),
+            Search::Found(_) | Search::Exhausted => {}
+            Search::GaveUp => {
+                gave_up = true;
+                break;
+            }
+        }
+    }
+    chains.sort_by(|a, b| searcher.cost(a, base).total_cmp(&searcher.cost(b, base)));
+    (chains, gave_up)
+}
+
+/// Restrictions on the artists and linking tracks a path may use.
+#[derive(Debug, Clone, Default)]
+pub struct PathConstraints {
+    /// Artist ids the path may not pass through.
+    pub exclude: Vec<String>,
+    /// Artist ids the path must pass through, in any order.
+    pub require: Vec<String>,
+    /// Every hop needs a linking track released in `from_year..=to_year`.
+    pub from_year: Option<u16>,
+    pub to_year: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PathOptions {
+    pub mode: PathMode,
+    /// How many paths to return. Defaults to 1, or in disjoint modes to every path of
+    /// the maximum set, up to `MAX_DISJOINT_PATHS`.
+    pub k: Option<usize>,
+    pub limits: SearchLimits,
+    pub constraints: PathConstraints,
+    /// Only used in weighted mode; see [`CollaborationWeights`].
+    pub recency_half_life: Option<f64>,
+}
+
+fn resolve_artists(graph: &CollaborationGraph, ids: &[String]) -> Result<Vec<u32>, String> {
+    ids.iter()
+        .map(|id| {
+            graph
+                .artist_index(id)
+                .ok_or_else(|| format!("Unknown artist {}", id))
+        })
+        .collect()
+}
+
+/// Finds paths between `from` and `to` according to `options.mode`, honoring
+/// `options.constraints`. Required artists are not supported in disjoint modes, and
+/// the hop limit does not apply to them.
+///
+/// The returned paths only carry ids; call [`resolve_details`] once the graph lock
+/// has been released to fill in artist names and track details from Scylla.
+pub fn find_paths(
+    graph: &CollaborationGraph,
+    from: &str,
+    to: &str,
+    options: &PathOptions,
+) -> PathOutcome {
+    let (source, target) = match (graph.artist_index(from), graph.artist_index(to)) {
+        (Some(source), Some(target)) => (source, target),
+        (None, _) => return PathOutcome::NotFound(format!("Unknown artist {}", from)),
+        (_, None) => return PathOutcome::NotFound(format!("Unknown artist {}", to)),
+    };
+    let constraints = &options.constraints;
+    let mut required = match resolve_artists(graph, &constraints.require) {
+        Ok(required) => required,
+        Err(reason) => return PathOutcome::NotFound(reason),
+    };
+    // Every path passes through its endpoints, and through an artist at most once
+    let mut seen = HashSet::from([source, target]);
+    required.retain(|artist| seen.insert(*artist));
+    if let (Some(from_year), Some(to_year)) = (constraints.from_year, constraints.to_year) {
+        if from_year > to_year {
+            return PathOutcome::Invalid(format!(
+                "from_year {} is after to_year {}",
+                from_year, to_year
+            ));
+        }
+    }
+    let disjoint = matches!(
+        options.mode,
+        PathMode::NodeDisjoint | PathMode::EdgeDisjoint
+    );
+    if !required.is_empty() && disjoint {
+        return PathOutcome::Invalid(
+            "Required artists are not supported in disjoint modes".to_string(),
+        );
+    }
+    let exclusions = Exclusions {
+        // Excluded artists that are not in the graph cannot appear on a path anyway
+        artists: constraints
+            .exclude
+            .iter()
+            .filter_map(|id| graph.artist_index(id))
+            .collect(),
+        edges: HashSet::new(),
+        years: match (constraints.from_year, constraints.to_year) {
+            (None, None) => None,
+            (from_year, to_year) => Some(from_year.unwrap_or(0)..=to_year.unwrap_or(u16::MAX)),
+        },
+    };
+    if let Some(&conflict) = [source, target]
+        .iter()
+        .chain(&required)
+        .find(|artist| exclusions.artists.contains(artist))
+    {
+        return PathOutcome::Invalid(format!(
+            "Artist {} is both excluded and part of the path",
+            graph.artist_id(conflict)
+        ));
+    }
+
+    let limits = options.limits.clamped();
+    let mut budget = Budget::new(limits.max_expansions);
+    let gave_up = PathOutcome::GaveUp {
+        expansions: limits.max_expansions,
+        reason: format!(
+            "Gave up after {} expansions without connecting {} and {}",
+            limits.max_expansions, from, to
+        ),
+    };
+    let not_found = PathOutcome::NotFound(if disjoint {
+        format!(
+            "No collaboration path between {} and {} matching the constraints",
+            from, to
+        )
+    } else {
+        format!(
+            "No collaboration path between {} and {} within {} hops matching the constraints",
+            from, to, limits.max_hops
+        )
+    });
+    let k = if disjoint {
+        options
+            .k
+            .map_or(MAX_DISJOINT_PATHS, |k| k.clamp(1, MAX_DISJOINT_PATHS))
+    } else {
+        options.k.map_or(1, |k| k.clamp(1, MAX_K))
+    };
+    let searcher = Searcher {
+        graph,
+        weights: (options.mode == PathMode::Weighted)
+            .then(|| CollaborationWeights::new(options.recency_half_life)),
+    };
+
+    let (chains, stopped_early) = match options.mode {
+        PathMode::Shortest | PathMode::Weighted if !required.is_empty() => {
+            let (mut chains, stopped_early) = required_paths(
+                &searcher,
+                source,
+                target,
+                &required,
+                limits.max_hops,
+                &exclusions,
+                &mut budget,
+            );
+            if chains.is_empty() {
+                return if stopped_early { gave_up } else { not_found };
+            }
+            chains.truncate(k);
+            (chains, stopped_early)
+        }
+        PathMode::Shortest | PathMode::Weighted => {
+            match searcher.search(source, target, limits.max_hops, &exclusions, &mut budget) {
+                Search::Found(shortest) => k_shortest_paths(
+                    &searcher,
+                    shortest,
+                    k,
+                    limits.max_hops,
+                    &exclusions,
+                    &mut budget,
+                ),
+                Search::Exhausted => return not_found,
+                Search::GaveUp => return gave_up,
+            }
+        }
+        PathMode::NodeDisjoint | PathMode::EdgeDisjoint if source == target => {
+            (vec![vec![source]], false)
+        }
+        PathMode::NodeDisjoint | PathMode::EdgeDisjoint => {
+            let search = if options.mode == PathMode::NodeDisjoint {
+                node_disjoint_paths
+            } else {
+                edge_disjoint_paths
+            };
+            let DisjointPaths { paths, complete } =
+                search(graph, source, target, k, &exclusions, &mut budget);
+            if paths.is_empty() {
+                return if complete { not_found } else { gave_up };
+            }
+            // Stopping at the `k` paths asked for is not stopping early
+            let wanted_more = options.k.is_none() || paths.len() < k;
+            (paths, !complete && wanted_more)
+        }
+    };
+
+    let paths = chains
+        .iter()
+        .map(|chain| ArtistPath {
+            weight: searcher
+                .weights
+                .map(|weights| weights.path_weight(graph, chain, &exclusions)),
+            ..build_path(graph, chain, &exclusions)
+        })
+        .collect();
+    if stopped_early {
+        PathOutcome::Partial {
+            paths,
+            reason: format!(
+                "Stopped after {} expansions; more paths may exist",
+                budget.used.min(limits.max_expansions)
+            ),
+        }
+    } else {
+        PathOutcome::Found(paths)
+    }
+}
+
+fn build_path(graph: &CollaborationGraph, chain: &[u32], exclusions: &Exclusions) -> ArtistPath {
+    let artists = chain
+        .iter()
+        .map(|&artist| Artist {
+            id: graph.artist_id(artist).to_string(),
+            name: String::new(),
+        })
+        .collect();
+    let hops = chain
+        .windows(2)
+        .map(|pair| {
+            let edge = graph
+                .edge(pair[0], pair[1])
+                .expect("consecutive path artists must be linked");
+            Hop {
+                from: graph.artist_id(pair[0]).to_string(),
+                to: graph.artist_id(pair[1]).to_string(),
+                tracks: exclusions
+                    .linking_tracks(graph, edge)
+                    .take(MAX_TRACKS_PER_HOP)
+                    .map(|track| PathTrack {
+                        id: graph.track_id(track).to_string(),
+                        ..PathTrack::default()
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    ArtistPath {
+        artists,
+        hops,
+        weight: None,
+    }
+}
+
+type TrackRow = (
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+async fn fetch_artist_names(
+    session: &Session,
+    ids: &[String],
+) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    let mut names = HashMap::new();
+    for chunk in ids.chunks(IN_QUERY_CHUNK_SIZE) {
+        let result = session
+            .query("SELECT id, name FROM music.artists WHERE id IN ?", (chunk,))
+            .await?;
+        for row in result.rows_typed_or_empty::<(String, String)>() {
+            let (id, name) = row?;
+            names.insert(id, name);
+        }
+    }
+    Ok(names)
+}
+
+async fn fetch_tracks(
+    session: &Session,
+    ids: &[String],
+) -> Result<HashMap<String, PathTrack>, Box<dyn Error>> {
+    let mut tracks = HashMap::new();
+    for chunk in ids.chunks(IN_QUERY_CHUNK_SIZE) {
+        let result = session
+            .query(
+                "SELECT id, name, preview_url, album_name, album_image FROM music.tracks WHERE id IN ?",
+                (chunk,),
+            )
+            .await?;
+        for row in result.rows_typed_or_empty::<TrackRow>() {
+            let (id, name, preview_url, album_name, album_image) = row?;
+            tracks.insert(
+                id.clone(),
+                PathTrack {
+                    id,
+                    name,
+                    preview_url,
+                    album_name,
+                    album_image,
+                },
+            );
+        }
+    }
+    Ok(tracks)
+}
+
+/// Fills in artist names and linking track details (name, preview, album, cover) from Scylla.
+pub async fn resolve_details(
+    session: &Session,
+    paths: &mut [ArtistPath],
+) -> Result<(), Box<dyn Error>> {
+    let artist_ids: Vec<String> = paths
+        .iter()
+        .flat_map(|path| path.artists.iter().map(|a| a.id.clone()))
+        .unique()
+        .collect();
+    let track_ids: Vec<String> = paths
+        .iter()
+        .flat_map(|path| path.hops.iter().flat_map(|h| h.tracks.iter()))
+        .map(|track| track.id.clone())
+        .unique()
+        .collect();
+    let artist_names = fetch_artist_names(session, &artist_ids).await?;
+    let tracks = fetch_tracks(session, &track_ids).await?;
+
+    for path in paths.iter_mut() {
+        for artist in path.artists.iter_mut() {
+            if let Some(name) = artist_names.get(&artist.id) {
+                artist.name = name.clone();
+            }
+        }
+        for track in path.hops.iter_mut().flat_map(|h| h.tracks.iter_mut()) {
+            if let Some(details) = tracks.get(&track.id) {
+                *track = details.clone();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a graph with one track per pair of linked artists.
+    fn graph(links: &[(&str, &str)]) -> CollaborationGraph {
+        let mut graph = CollaborationGraph::default();
+        for (position, (a, b)) in links.iter().enumerate() {
+            graph.add_track(
+                &format!("t{}", position),
+                &[a.to_string(), b.to_string()],
+                None,
+            );
+        }
+        graph
+    }
+
+    fn ids(graph: &CollaborationGraph, chain: &[u32]) -> Vec<String> {
+        chain
+            .iter()
+            .map(|&artist| graph.artist_id(artist).to_string())
+            .collect()
+    }
+
+    fn search(
+        graph: &CollaborationGraph,
+        from: &str,
+        to: &str,
+        max_hops: usize,
+        exclusions: &Exclusions,
+        max_expansions: usize,
+    ) -> Search {
+        bidirectional_search(
+            graph,
+            graph.artist_index(from).unwrap(),
+            graph.artist_index(to).unwrap(),
+            max_hops,
+            exclusions,
+            &mut Budget::new(max_expansions),
+        )
+    }
+
+    #[test]
+    fn bidirectional_search_finds_shortest_chain() {
+        let graph = graph(&[
+            ("a", "b"),
+            ("b", "c"),
+            ("c", "d"),
+            ("d", "e"),
+            ("a", "x"),
+            ("x", "e"),
+        ]);
+        match search(&graph, "a", "e", 6, &Exclusions::default(), 100) {
+            Search::Found(chain) => assert_eq!(ids(&graph, &chain), ["a", "x", "e"]),
+            _ => panic!("expected a path"),
+        }
+    }
+
+    #[test]
+    fn bidirectional_search_of_an_artist_to_itself_is_one_artist() {
+        let graph = graph(&[("a", "b")]);
+        match search(&graph, "a", "a", 6, &Exclusions::default(), 100) {
+            Search::Found(chain) => assert_eq!(ids(&graph, &chain), ["a"]),
+            _ => panic!("expected a path"),
+        }
+    }
+
+    #[test]
+    fn bidirectional_search_respects_max_hops() {
+        let graph = graph(&[("a", "b"), ("b", "c"), ("c", "d")]);
+        assert!(matches!(
+            search(&graph, "a", "d", 2, &Exclusions::default(), 100),
+            Search::Exhausted
+        ));
+        assert!(matches!(
+            search(&graph, "a", "d", 3, &Exclusions::default(), 100),
+            Search::Found(_)
+        ));
+    }
+
+    #[test]
+    fn bidirectional_search_avoids_excluded_artists() {
+        let graph = graph(&[("a", "b"), ("b", "d"), ("a", "c"), ("c", "e"), ("e", "d")]);
+        let exclusions = Exclusions {
+            artists: HashSet::from([graph.artist_index("b").unwrap()]),
+            ..Exclusions::default()
+        };
+        match search(&graph, "a", "d", 6, &exclusions, 100) {
+            Search::Found(chain) => assert_eq!(ids(&graph, &chain), ["a", "c", "e", "d"]),
+            _ => panic!("expected a path"),
+        }
+    }
+
+    #[test]
+    fn bidirectional_search_gives_up_when_the_budget_is_spent() {
+        let graph = graph(&[("a", "b"), ("b", "c"), ("c", "d")]);
+        assert!(matches!(
+            search(&graph, "a", "d", 6, &Exclusions::default(), 1),
+            Search::GaveUp
+        ));
+    }
+
+    #[test]
+    fn bidirectional_search_reports_disconnected_artists() {
+        let graph = graph(&[("a", "b"), ("c", "d")]);
+        assert!(matches!(
+            search(&graph, "a", "d", 6, &Exclusions::default(), 100),
+            Search::Exhausted
+        ));
+    }
+
+    fn k_shortest(
+        graph: &CollaborationGraph,
+        k: usize,
+        max_expansions: usize,
+    ) -> (Vec<Vec<String>>, bool) {
+        let searcher = Searcher {
+            graph,
+            weights: None,
+        };
+        let mut budget = Budget::new(max_expansions);
+        let Search::Found(shortest) = search(graph, "a", "e", 6, &Exclusions::default(), 100)
+        else {
+            panic!("expected a path");
+        };
+        let (paths, stopped_early) = k_shortest_paths(
+            &searcher,
+            shortest,
+            k,
+            6,
+            &Exclusions::default(),
+            &mut budget,
+        );
+        (
+            paths.iter().map(|path| ids(graph, path)).collect(),
+            stopped_early,
+        )
+    }
+
+    fn three_routes() -> CollaborationGraph {
+        graph(&[
+            ("a", "b"),
+            ("b", "e"),
+            ("a", "c"),
+            ("c", "e"),
+            ("a", "d"),
+            ("d", "f"),
+            ("f", "e"),
+            ("b", "c"),
+        ])
+    }
+
+    #[test]
+    fn k_shortest_paths_are_loopless_distinct_and_shortest_first() {
+        let graph = three_routes();
+        let (paths, stopped_early) = k_shortest(&graph, 10, 10_000);
+        assert!(!stopped_early);
+        let lengths: Vec<usize> = paths.iter().map(Vec::len).collect();
+        assert_eq!(&lengths[..3], [3, 3, 4]);
+        assert!(lengths.windows(2).all(|pair| pair[0] <= pair[1]));
+        for path in &paths {
+            assert_eq!(path.iter().unique().count(), path.len(), "{:?} loops", path);
+        }
+        assert_eq!(paths.iter().unique().count(), paths.len());
+        // a-b-e, a-c-e, a-d-f-e, a-b-c-e and a-c-b-e
+        assert_eq!(paths.len(), 5);
+    }
+
+    #[test]
+    fn k_shortest_paths_stops_at_k() {
+        let graph = three_routes();
+        let (paths, _) = k_shortest(&graph, 2, 10_000);
+        assert_eq!(paths.len(), 2);
+        assert!(paths.iter().all(|path| path.len() == 3));
+    }
+
+    #[test]
+    fn k_shortest_paths_keeps_what_it_found_when_the_budget_runs_out() {
+        let graph = three_routes();
+        let (paths, stopped_early) = k_shortest(&graph, 10, 1);
+        assert!(stopped_early);
+        assert_eq!(paths.len(), 1);
+    }
+
+    #[test]
+    fn search_via_reroutes_around_a_blocking_waypoint_segment() {
+        // The shortest s-w segment passes b, the only artist leading on from w to t
+        let graph = graph(&[
+            ("s", "b"),
+            ("b", "w"),
+            ("b", "t"),
+            ("s", "c"),
+            ("c", "d"),
+            ("d", "w"),
+        ]);
+        let searcher = Searcher {
+            graph: &graph,
+            weights: None,
+        };
+        let waypoints: Vec<u32> = ["s", "w", "t"]
+            .iter()
+            .map(|id| graph.artist_index(id).unwrap())
+            .collect();
+        let result = search_via(
+            &searcher,
+            &waypoints,
+            6,
+            &Exclusions::default(),
+            &mut Budget::new(10_000),
+        );
+        match result {
+            Search::Found(chain) => {
+                assert_eq!(ids(&graph, &chain), ["s", "c", "d", "w", "b", "t"])
+            }
+            _ => panic!("expected a path"),
+        }
+    }
+
+    fn options(mode: PathMode, k: Option<usize>) -> PathOptions {
+        PathOptions {
+            mode,
+            k,
+            limits: SearchLimits::default(),
+            constraints: PathConstraints::default(),
+            recency_half_life: None,
+        }
+    }
+
+    fn found(outcome: PathOutcome) -> Vec<Vec<String>> {
+        match outcome {
+            PathOutcome::Found(paths) => paths
+                .iter()
+                .map(|path| {
+                    path.artists
+                        .iter()
+                        .map(|artist| artist.id.clone())
+                        .collect()
+                })
+                .collect(),
+            other => panic!("expected paths, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn disjoint_modes_return_k_paths() {
+        let graph = three_routes();
+        let all = found(find_paths(
+            &graph,
+            "a",
+            "e",
+            &options(PathMode::NodeDisjoint, None),
+        ));
+        assert_eq!(all.len(), 3);
+        let two = found(find_paths(
+            &graph,
+            "a",
+            "e",
+            &options(PathMode::EdgeDisjoint, Some(2)),
+        ));
+        assert_eq!(two.len(), 2);
+    }
+
+    #[test]
+    fn disjoint_not_found_does_not_mention_hops() {
+        let graph = graph(&[("a", "b"), ("c", "d")]);
+        match find_paths(&graph, "a", "d", &options(PathMode::NodeDisjoint, None)) {
+            PathOutcome::NotFound(reason) => assert!(!reason.contains("hops"), "{}", reason),
+            other => panic!("expected not found, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn required_endpoints_and_duplicates_are_ignored() {
+        let graph = graph(&[("s", "m"), ("m", "t")]);
+        for require in [vec!["t"], vec!["s"], vec!["m", "m"]] {
+            let mut options = options(PathMode::Shortest, None);
+            options.constraints.require = require.iter().map(|id| id.to_string()).collect();
+            let paths = found(find_paths(&graph, "s", "t", &options));
+            assert_eq!(paths, [["s", "m", "t"]], "require {:?}", require);
+        }
+    }
+
+    #[test]
+    fn reversed_year_window_is_invalid() {
+        let graph = graph(&[("s", "t")]);
+        let mut options = options(PathMode::Shortest, None);
+        options.constraints.from_year = Some(2020);
+        options.constraints.to_year = Some(2010);
+        assert!(matches!(
+            find_paths(&graph, "s", "t", &options),
+            PathOutcome::Invalid(_)
+        ));
+    }
+
+    #[test]
+    fn search_limits_are_capped() {
+        let limits = SearchLimits {
+            max_hops: usize::MAX,
+            max_expansions: usize::MAX,
+        }
+        .clamped();
+        assert_eq!(limits.max_hops, MAX_HOPS);
+        assert_eq!(limits.max_expansions, MAX_EXPANSIONS);
+    }
+}
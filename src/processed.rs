@@ -0,0 +1,119 @@
+use std::error::Error;
+
+use fred::prelude::*;
+use fred::types::{RedisValue, Scanner};
+use futures::{Stream, StreamExt};
+
+use crate::lock::ArtistLock;
+
+/// Ids of every artist crawled at least once
+const PROCESSED_ARTISTS: &str = "processed_artists";
+/// Processed artist id -> the smallest depth it was crawled at
+const PROCESSED_DEPTHS: &str = "processed_artist_depths";
+/// Ids checked per `SMISMEMBER`/`HMGET` round trip
+const CHUNK_SIZE: usize = 1000;
+
+/// KEYS: artist lock, processed set, depths, ingested albums.
+/// ARGV: lock token, artist id, depth, album ids...
+const INSERT_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call("SADD", KEYS[2], ARGV[2])
+local known = redis.call("HGET", KEYS[3], ARGV[2])
+if not known or tonumber(ARGV[3]) < tonumber(known) then
+    redis.call("HSET", KEYS[3], ARGV[2], ARGV[3])
+end
+for i = 4, #ARGV do
+    redis.call("SADD", KEYS[4], ARGV[i])
+end
+return 1
+"#;
+
+/// The set of crawled artists in Redis. Lookups cost one round trip per
+/// `CHUNK_SIZE` ids asked about, however many artists have been crawled.
+pub struct ProcessedArtists<'a> {
+    client: &'a RedisClient,
+}
+
+impl<'a> ProcessedArtists<'a> {
+    pub fn new(client: &'a RedisClient) -> Self {
+        ProcessedArtists { client }
+    }
+
+    /// Whether each of `artist_ids` has been crawled, in order.
+    pub async fn contains(&self, artist_ids: &[&str]) -> Result<Vec<bool>, Box<dyn Error>> {
+        let mut found = Vec::with_capacity(artist_ids.len());
+        for chunk in artist_ids.chunks(CHUNK_SIZE) {
+            let members: Vec<bool> = self
+                .client
+                .smismember(PROCESSED_ARTISTS, chunk.to_vec())
+                .await?;
+            found.extend(members);
+        }
+        Ok(found)
+    }
+
+    /// The depth each of `artist_ids` was crawled at, in order. `None` for artists
+    /// not crawled, or crawled before depths were recorded.
+    pub async fn depths(&self, artist_ids: &[&str]) -> Result<Vec<Option<i32>>, Box<dyn Error>> {
+        let mut depths = Vec::with_capacity(artist_ids.len());
+        for chunk in artist_ids.chunks(CHUNK_SIZE) {
+            let chunk_depths: Vec<Option<i32>> =
+                self.client.hmget(PROCESSED_DEPTHS, chunk.to_vec()).await?;
+            depths.extend(chunk_depths);
+        }
+        Ok(depths)
+    }
+
+    pub async fn depth(&self, artist_id: &str) -> Result<Option<i32>, Box<dyn Error>> {
+        Ok(self.client.hget(PROCESSED_DEPTHS, artist_id).await?)
+    }
+
+    /// Marks the artist crawled at `depth`, keeping the smallest depth seen, and adds
+    /// `albums` to its `ingested_key` set. Only does so while `lock` is still held;
+    /// returns false, writing nothing, once it has been lost.
+    pub async fn insert(
+        &self,
+        lock: &ArtistLock,
+        artist_id: &str,
+        depth: i32,
+        ingested_key: &str,
+        albums: &[&str],
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut args: Vec<RedisValue> = vec![lock.token.into(), artist_id.into(), depth.into()];
+        args.extend(albums.iter().map(|&album| RedisValue::from(album)));
+        let inserted: i64 = self
+            .client
+            .eval(
+                INSERT_SCRIPT,
+                vec![
+                    lock.key(),
+                    PROCESSED_ARTISTS,
+                    PROCESSED_DEPTHS,
+                    ingested_key,
+                ],
+                args,
+            )
+            .await?;
+        Ok(inserted == 1)
+    }
+
+    /// Every crawled artist, in pages of about `count` ids read with `SSCAN`. An
+    /// artist crawled while scanning may or may not be included.
+    pub fn scan(&self, count: u32) -> impl Stream<Item = Result<Vec<String>, Box<dyn Error>>> + 'a {
+        self.client
+            .sscan(PROCESSED_ARTISTS, "*", Some(count))
+            .map(|page| {
+                let mut page = page?;
+                let artist_ids = page
+                    .take_results()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(RedisValue::convert)
+                    .collect::<Result<Vec<String>, _>>()?;
+                page.next()?;
+                Ok(artist_ids)
+            })
+    }
+}
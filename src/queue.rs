@@ -0,0 +1,469 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use fred::prelude::RedisClient;
+use scylla::Session;
+
+use crate::clock::millis_from_now;
+use crate::redis_queue::RedisQueue;
+use crate::task::{
+    complete_task, dequeue_task, enqueue_tasks, fail_task, list_dead_letters, max_attempts,
+    reap_expired_leases, renew_lease, requeue_dead_letters, setup_task_table, ArtistTask,
+    DeadLetter, Discovery, NackOutcome,
+};
+
+/// The crawl frontier. Workers lease artists with `dequeue`, keep the lease alive with
+/// `renew` while processing, and finish with `ack` or `nack`. Leases that run out are
+/// handed back by `reap_expired`.
+#[async_trait(?Send)]
+pub trait TaskQueue: Send + Sync {
+    /// Adds newly discovered artists as pending, and raises the priority of artists
+    /// still pending by the discovery weight, keeping their smallest depth. Leased
+    /// artists are left alone.
+    async fn enqueue(&self, discoveries: Vec<Discovery>) -> Result<(), Box<dyn Error>>;
+
+    /// Claims the highest-priority pending artist for `consumer` until `lease` elapses.
+    async fn dequeue(
+        &self,
+        consumer: &str,
+        lease: Duration,
+    ) -> Result<Option<ArtistTask>, Box<dyn Error>>;
+
+    /// Pushes the lease expiry forward. Returns false if the lease was lost.
+    async fn renew(&self, task: &mut ArtistTask, lease: Duration) -> Result<bool, Box<dyn Error>>;
+
+    /// Removes a processed artist from the queue. Returns false, changing nothing, if
+    /// the task's lease has passed to another consumer.
+    async fn ack(&self, task: &ArtistTask) -> Result<bool, Box<dyn Error>>;
+
+    /// Records a failed attempt and returns the artist to pending, or dead-letters it
+    /// after `TASK_MAX_ATTEMPTS` failures. Changes nothing if the task's lease has
+    /// passed to another consumer.
+    async fn nack(&self, task: &ArtistTask, error: &str) -> Result<NackOutcome, Box<dyn Error>>;
+
+    /// Returns artists whose lease expired to pending. Returns how many were reclaimed.
+    async fn reap_expired(&self) -> Result<usize, Box<dyn Error>>;
+
+    async fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, Box<dyn Error>>;
+
+    /// Moves dead-lettered artists back to pending with a fresh attempt count.
+    /// Returns the ids that were actually dead-lettered.
+    async fn requeue_dead_letters(
+        &self,
+        artist_ids: &[String],
+    ) -> Result<Vec<String>, Box<dyn Error>>;
+}
+
+/// Builds the queue selected by `TASK_QUEUE` (`scylla`, `redis` or `memory`),
+/// creating whatever tables or consumer groups it needs.
+pub async fn queue_from_env(
+    session: Arc<Session>,
+    redis_client: RedisClient,
+) -> Result<Arc<dyn TaskQueue>, Box<dyn Error>> {
+    let backend = std::env::var("TASK_QUEUE").unwrap_or("scylla".to_string());
+    let queue: Arc<dyn TaskQueue> = match backend.as_str() {
+        "scylla" => {
+            setup_task_table(&session).await?;
+            Arc::new(ScyllaQueue { session })
+        }
+        "redis" => Arc::new(RedisQueue::new(redis_client).await?),
+        "memory" => Arc::new(MemoryQueue::default()),
+        other => return Err(format!("Unknown TASK_QUEUE backend {}", other).into()),
+    };
+    println!("Using the {} task queue", backend);
+    Ok(queue)
+}
+
+/// `music.artist_tasks` and its bucketed, priority-ordered `music.task_queue` index.
+pub struct ScyllaQueue {
+    session: Arc<Session>,
+}
+
+#[async_trait(?Send)]
+impl TaskQueue for ScyllaQueue {
+    async fn enqueue(&self, discoveries: Vec<Discovery>) -> Result<(), Box<dyn Error>> {
+        enqueue_tasks(&self.session, discoveries).await
+    }
+
+    async fn dequeue(
+        &self,
+        consumer: &str,
+        lease: Duration,
+    ) -> Result<Option<ArtistTask>, Box<dyn Error>> {
+        dequeue_task(&self.session, consumer, lease).await
+    }
+
+    async fn renew(&self, task: &mut ArtistTask, lease: Duration) -> Result<bool, Box<dyn Error>> {
+        renew_lease(&self.session, task, lease).await
+    }
+
+    async fn ack(&self, task: &ArtistTask) -> Result<bool, Box<dyn Error>> {
+        complete_task(&self.session, task).await
+    }
+
+    async fn nack(&self, task: &ArtistTask, error: &str) -> Result<NackOutcome, Box<dyn Error>> {
+        fail_task(&self.session, task, error).await
+    }
+
+    async fn reap_expired(&self) -> Result<usize, Box<dyn Error>> {
+        reap_expired_leases(&self.session).await
+    }
+
+    async fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, Box<dyn Error>> {
+        list_dead_letters(&self.session, limit).await
+    }
+
+    async fn requeue_dead_letters(
+        &self,
+        artist_ids: &[String],
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        requeue_dead_letters(&self.session, artist_ids).await
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// Pending artists, highest priority first, then in discovery order
+    pending: BTreeSet<(Reverse<i32>, u64, String)>,
+    /// Priority and pending position of every pending or leased artist
+    queued: HashMap<String, (i32, u64)>,
+    next_position: u64,
+    depths: HashMap<String, i32>,
+    /// Owner and expiry of leased artists
+    leases: HashMap<String, (String, i64)>,
+    /// Failed attempts and last error
+    failures: HashMap<String, (i32, String)>,
+    created: HashMap<String, i64>,
+    dead: BTreeMap<String, DeadLetter>,
+}
+
+impl MemoryState {
+    fn make_pending(&mut self, artist_id: &str, priority: i32) {
+        self.unpend(artist_id);
+        let position = self.next_position;
+        self.next_position += 1;
+        self.pending
+            .insert((Reverse(priority), position, artist_id.to_string()));
+        self.queued
+            .insert(artist_id.to_string(), (priority, position));
+    }
+
+    fn unpend(&mut self, artist_id: &str) {
+        if let Some(&(priority, position)) = self.queued.get(artist_id) {
+            self.pending
+                .remove(&(Reverse(priority), position, artist_id.to_string()));
+        }
+    }
+
+    fn holds_lease(&self, task: &ArtistTask) -> bool {
+        self.leases
+            .get(&task.artist_id)
+            .is_some_and(|(owner, _)| *owner == task.lease_owner)
+    }
+
+    fn priority(&self, artist_id: &str) -> i32 {
+        self.queued
+            .get(artist_id)
+            .map_or(1, |&(priority, _)| priority)
+    }
+
+    fn forget(&mut self, artist_id: &str) {
+        self.unpend(artist_id);
+        self.queued.remove(artist_id);
+        self.depths.remove(artist_id);
+        self.leases.remove(artist_id);
+        self.failures.remove(artist_id);
+        self.created.remove(artist_id);
+    }
+
+    fn push(&mut self, discovery: Discovery) {
+        let Discovery {
+            artist_id,
+            weight,
+            depth,
+        } = discovery;
+        if !self.queued.contains_key(&artist_id) {
+            self.created
+                .insert(artist_id.clone(), millis_from_now(Duration::ZERO));
+            self.depths.insert(artist_id.clone(), depth);
+            self.make_pending(&artist_id, weight);
+        } else if !self.leases.contains_key(&artist_id) {
+            let raised = self.priority(&artist_id).saturating_add(weight);
+            self.make_pending(&artist_id, raised);
+            let known = self.depths.entry(artist_id).or_insert(depth);
+            *known = (*known).min(depth);
+        }
+    }
+
+    /// Drops the lease and puts the artist back in line at its current priority.
+    fn release(&mut self, artist_id: &str) {
+        self.leases.remove(artist_id);
+        let priority = self.priority(artist_id);
+        self.make_pending(artist_id, priority);
+    }
+}
+
+/// Process-local queue for running without Scylla or Redis. Nothing survives a restart.
+#[derive(Debug, Default)]
+pub struct MemoryQueue {
+    state: Mutex<MemoryState>,
+}
+
+#[async_trait(?Send)]
+impl TaskQueue for MemoryQueue {
+    async fn enqueue(&self, discoveries: Vec<Discovery>) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        for discovery in discoveries {
+            state.push(discovery);
+        }
+        Ok(())
+    }
+
+    async fn dequeue(
+        &self,
+        consumer: &str,
+        lease: Duration,
+    ) -> Result<Option<ArtistTask>, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let Some((Reverse(priority), _, artist_id)) = state.pending.pop_first() else {
+            return Ok(None);
+        };
+        let lease_expires_at = millis_from_now(lease);
+        state
+            .leases
+            .insert(artist_id.clone(), (consumer.to_string(), lease_expires_at));
+        let depth = state.depths.get(&artist_id).copied().unwrap_or(0);
+        Ok(Some(ArtistTask {
+            artist_id,
+            priority,
+            depth,
+            lease_owner: consumer.to_string(),
+            lease_expires_at,
+        }))
+    }
+
+    async fn renew(&self, task: &mut ArtistTask, lease: Duration) -> Result<bool, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        match state.leases.get_mut(&task.artist_id) {
+            Some((owner, expires_at)) if *owner == task.lease_owner => {
+                *expires_at = millis_from_now(lease);
+                task.lease_expires_at = *expires_at;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn ack(&self, task: &ArtistTask) -> Result<bool, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.holds_lease(task) {
+            return Ok(false);
+        }
+        state.forget(&task.artist_id);
+        Ok(true)
+    }
+
+    async fn nack(&self, task: &ArtistTask, error: &str) -> Result<NackOutcome, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.holds_lease(task) {
+            return Ok(NackOutcome::LeaseLost);
+        }
+        let artist_id = task.artist_id.as_str();
+        let failures = state
+            .failures
+            .entry(artist_id.to_string())
+            .or_insert((0, String::new()));
+        failures.0 += 1;
+        failures.1 = error.to_string();
+        let attempts = failures.0;
+
+        if attempts >= max_attempts() {
+            let dead_letter = DeadLetter {
+                artist_id: artist_id.to_string(),
+                attempts,
+                last_error: Some(error.to_string()),
+                created_at: state.created.get(artist_id).copied(),
+                failed_at: Some(millis_from_now(Duration::ZERO)),
+                depth: state.depths.get(artist_id).copied().unwrap_or(0),
+            };
+            state.dead.insert(artist_id.to_string(), dead_letter);
+            state.forget(artist_id);
+            return Ok(NackOutcome::DeadLettered);
+        }
+
+        state.release(artist_id);
+        Ok(NackOutcome::Requeued)
+    }
+
+    async fn reap_expired(&self) -> Result<usize, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let now = millis_from_now(Duration::ZERO);
+        let expired: Vec<String> = state
+            .leases
+            .iter()
+            .filter(|(_, (_, expires_at))| *expires_at <= now)
+            .map(|(artist_id, _)| artist_id.clone())
+            .collect();
+        for artist_id in &expired {
+            state.release(artist_id);
+        }
+        Ok(expired.len())
+    }
+
+    async fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, Box<dyn Error>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.dead.values().take(limit).cloned().collect())
+    }
+
+    async fn requeue_dead_letters(
+        &self,
+        artist_ids: &[String],
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut state = self.state.lock().unwrap();
+        let mut requeued = Vec::new();
+        for artist_id in artist_ids {
+            if let Some(dead_letter) = state.dead.remove(artist_id) {
+                state.push(Discovery {
+                    artist_id: artist_id.clone(),
+                    weight: 1,
+                    depth: dead_letter.depth,
+                });
+                requeued.push(artist_id.clone());
+            }
+        }
+        Ok(requeued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    fn discovery(artist_id: &str, weight: i32, depth: i32) -> Discovery {
+        Discovery {
+            artist_id: artist_id.to_string(),
+            weight,
+            depth,
+        }
+    }
+
+    async fn drain(queue: &MemoryQueue) -> Vec<String> {
+        let mut artist_ids = Vec::new();
+        while let Some(task) = queue.dequeue("worker", LEASE).await.unwrap() {
+            artist_ids.push(task.artist_id);
+        }
+        artist_ids
+    }
+
+    #[tokio::test]
+    async fn dequeues_by_priority_then_discovery_order() {
+        let queue = MemoryQueue::default();
+        queue
+            .enqueue(vec![
+                discovery("a", 1, 0),
+                discovery("b", 1, 1),
+                discovery("c", 2, 1),
+            ])
+            .await
+            .unwrap();
+        // Rediscovering b raises it above c
+        queue.enqueue(vec![discovery("b", 2, 2)]).await.unwrap();
+
+        let task = queue.dequeue("worker", LEASE).await.unwrap().unwrap();
+        assert_eq!((task.artist_id.as_str(), task.priority), ("b", 3));
+        assert_eq!(task.depth, 1);
+        assert_eq!(drain(&queue).await, ["c", "a"]);
+    }
+
+    #[tokio::test]
+    async fn leased_artists_are_not_rediscovered() {
+        let queue = MemoryQueue::default();
+        queue.enqueue(vec![discovery("a", 1, 0)]).await.unwrap();
+        let task = queue.dequeue("worker", LEASE).await.unwrap().unwrap();
+        queue.enqueue(vec![discovery("a", 5, 0)]).await.unwrap();
+
+        assert!(queue.dequeue("worker", LEASE).await.unwrap().is_none());
+        assert!(queue.ack(&task).await.unwrap());
+        assert!(queue.dequeue("worker", LEASE).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reaps_expired_leases_and_fences_out_the_old_holder() {
+        let queue = MemoryQueue::default();
+        queue.enqueue(vec![discovery("a", 1, 0)]).await.unwrap();
+        let mut stale = queue
+            .dequeue("first", Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(queue.reap_expired().await.unwrap(), 1);
+        let mut task = queue.dequeue("second", LEASE).await.unwrap().unwrap();
+        assert_eq!(task.artist_id, "a");
+
+        assert!(!queue.renew(&mut stale, LEASE).await.unwrap());
+        assert!(!queue.ack(&stale).await.unwrap());
+        assert!(matches!(
+            queue.nack(&stale, "stalled").await.unwrap(),
+            NackOutcome::LeaseLost
+        ));
+        assert!(queue.renew(&mut task, LEASE).await.unwrap());
+        assert_eq!(queue.reap_expired().await.unwrap(), 0);
+        assert!(queue.ack(&task).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn nack_requeues_then_dead_letters_after_max_attempts() {
+        let queue = MemoryQueue::default();
+        queue.enqueue(vec![discovery("a", 1, 2)]).await.unwrap();
+        for attempt in 1..max_attempts() {
+            let task = queue.dequeue("worker", LEASE).await.unwrap().unwrap();
+            let outcome = queue.nack(&task, &format!("attempt {}", attempt)).await;
+            assert!(matches!(outcome.unwrap(), NackOutcome::Requeued));
+        }
+        let task = queue.dequeue("worker", LEASE).await.unwrap().unwrap();
+        assert!(matches!(
+            queue.nack(&task, "last").await.unwrap(),
+            NackOutcome::DeadLettered
+        ));
+        assert!(queue.dequeue("worker", LEASE).await.unwrap().is_none());
+
+        let dead_letters = queue.dead_letters(10).await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, max_attempts());
+        assert_eq!(dead_letters[0].last_error.as_deref(), Some("last"));
+        assert_eq!(dead_letters[0].depth, 2);
+    }
+
+    #[tokio::test]
+    async fn requeued_dead_letters_start_over() {
+        let queue = MemoryQueue::default();
+        queue.enqueue(vec![discovery("a", 1, 3)]).await.unwrap();
+        for _ in 0..max_attempts() {
+            let task = queue.dequeue("worker", LEASE).await.unwrap().unwrap();
+            queue.nack(&task, "failed").await.unwrap();
+        }
+
+        let requeued = queue
+            .requeue_dead_letters(&["a".to_string(), "unknown".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(requeued, ["a"]);
+        assert!(queue.dead_letters(10).await.unwrap().is_empty());
+
+        let task = queue.dequeue("worker", LEASE).await.unwrap().unwrap();
+        assert_eq!((task.artist_id.as_str(), task.depth), ("a", 3));
+        assert!(matches!(
+            queue.nack(&task, "failed again").await.unwrap(),
+            NackOutcome::Requeued
+        ));
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    error::Error,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use fred::prelude::*;
+
+use crate::env::env_or;
+
+/// Token bucket state shared by every process using the Redis limiter
+const BUCKET_KEY: &str = "rate_limit:spotify:bucket";
+/// Set with a TTL while Spotify has asked everyone to back off
+const PAUSE_KEY: &str = "rate_limit:spotify:paused";
+
+/// Takes a token from the bucket in `KEYS[1]`, refilled at `ARGV[1]` tokens a second
+/// up to `ARGV[2]`. Returns 0 when a token was taken, otherwise milliseconds to wait
+/// before asking again. Uses the Redis clock so hosts with skewed clocks agree.
+const TAKE_SCRIPT: &str = r#"
+local paused = redis.call("PTTL", KEYS[2])
+if paused > 0 then
+    return paused
+end
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call("TIME")
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+local state = redis.call("HMGET", KEYS[1], "tokens", "updated")
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate / 1000)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) * 1000 / rate)
+end
+redis.call("HSET", KEYS[1], "tokens", tostring(tokens), "updated", now)
+redis.call("PEXPIRE", KEYS[1], 60000)
+return wait
+"#;
+
+/// Sets the pause in `KEYS[1]` to expire in `ARGV[1]` milliseconds, unless it already
+/// expires later.
+const PAUSE_SCRIPT: &str = r#"
+if redis.call("PTTL", KEYS[1]) < tonumber(ARGV[1]) then
+    redis.call("SET", KEYS[1], 1, "PX", ARGV[1])
+end
+return 0
+"#;
+
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket in front of every Spotify API request, read from the environment:
+/// `SPOTIFY_RATE_LIMIT` requests a second (default 10), bursts of up to
+/// `SPOTIFY_RATE_BURST` (default the rate). With `SPOTIFY_RATE_LIMIT_SHARED=true`
+/// the bucket lives in Redis and is shared by every instance.
+///
+/// A 429 pauses all requests for its `Retry-After`, across instances when shared.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    redis_client: Option<RedisClient>,
+    bucket: Mutex<Bucket>,
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn from_env(redis_client: Option<RedisClient>) -> Self {
+        let rate: f64 = env_or("SPOTIFY_RATE_LIMIT", 10.0_f64).max(0.1);
+        let burst: f64 = env_or("SPOTIFY_RATE_BURST", rate).max(1.0);
+        let shared = env_or("SPOTIFY_RATE_LIMIT_SHARED", false);
+        RateLimiter {
+            rate,
+            burst,
+            redis_client: redis_client.filter(|_| shared),
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+            paused_until: Mutex::new(None),
+        }
+    }
+
+    /// Takes a token if one is left, otherwise returns how long until one is.
+    fn take_local(&self) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate)
+        }
+    }
+
+    async fn take(&self) -> Result<Duration, Box<dyn Error>> {
+        let paused = self
+            .paused_until
+            .lock()
+            .unwrap()
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .unwrap_or_default();
+        if !paused.is_zero() {
+            return Ok(paused);
+        }
+        let Some(redis_client) = &self.redis_client else {
+            return Ok(self.take_local());
+        };
+        let wait: i64 = redis_client
+            .eval(
+                TAKE_SCRIPT,
+                vec![BUCKET_KEY, PAUSE_KEY],
+                vec![self.rate.to_string(), self.burst.to_string()],
+            )
+            .await?;
+        Ok(Duration::from_millis(wait.max(0) as u64))
+    }
+
+    /// Waits until a request may be sent.
+    pub async fn acquire(&self) -> Result<(), Box<dyn Error>> {
+        loop {
+            let wait = self.take().await?;
+            if wait.is_zero() {
+                return Ok(());
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Holds back every request for `duration`, as asked by a 429's `Retry-After`.
+    /// Never shortens a longer pause already in place.
+    pub async fn pause(&self, duration: Duration) -> Result<(), Box<dyn Error>> {
+        let until = Instant::now() + duration;
+        {
+            let mut paused_until = self.paused_until.lock().unwrap();
+            if paused_until.is_none_or(|paused| paused < until) {
+                *paused_until = Some(until);
+            }
+        }
+        if let Some(redis_client) = &self.redis_client {
+            redis_client
+                .eval::<(), _, _, _>(
+                    PAUSE_SCRIPT,
+                    vec![PAUSE_KEY],
+                    vec![duration.as_millis().max(1) as i64],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Installs the process-wide limiter. Call once at startup; requests sent before
+/// fall back to a local limiter configured from the environment.
+pub fn init_rate_limiter(redis_client: RedisClient) {
+    let _ = RATE_LIMITER.set(RateLimiter::from_env(Some(redis_client)));
+}
+
+pub fn rate_limiter() -> &'static RateLimiter {
+    RATE_LIMITER.get_or_init(|| RateLimiter::from_env(None))
+}
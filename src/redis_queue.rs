@@ -0,0 +1,418 @@
+use std::{collections::HashMap, error::Error, time::Duration};
+
+use async_trait::async_trait;
+use fred::prelude::*;
+use fred::types::RedisValue;
+
+use crate::clock::millis_from_now;
+use crate::queue::TaskQueue;
+use crate::task::{max_attempts, ArtistTask, DeadLetter, Discovery, NackOutcome};
+
+const STREAM_PREFIX: &str = "artist_tasks:stream";
+/// Priorities are bucketed into this many tiers by their binary logarithm, each tier
+/// its own stream: 1, 2-3, 4-7, ... and everything from 128 up.
+const TIERS: u32 = 8;
+const GROUP: &str = "crawlers";
+/// Artists with an entry in a stream, pending or leased
+const QUEUED: &str = "artist_tasks:queued";
+/// Artist id -> priority
+const PRIORITIES: &str = "artist_tasks:priorities";
+/// Artist id -> hops from the nearest seed
+const DEPTHS: &str = "artist_tasks:depths";
+/// Artist id -> `<tier>/<stream entry id>` of its current entry
+const ENTRIES: &str = "artist_tasks:entries";
+/// Artist id -> consumer holding the lease
+const OWNERS: &str = "artist_tasks:owners";
+/// Leased artist ids scored by lease expiry in milliseconds
+const LEASES: &str = "artist_tasks:leases";
+const ATTEMPTS: &str = "artist_tasks:attempts";
+const ERRORS: &str = "artist_tasks:errors";
+const CREATED: &str = "artist_tasks:created";
+/// Artist id -> JSON `DeadLetter`
+const DEAD_LETTERS: &str = "artist_tasks:dead_letters";
+
+fn stream(tier: u32) -> String {
+    format!("{}:{}", STREAM_PREFIX, tier)
+}
+
+/// Every key the queue scripts touch. The streams come last, lowest tier first, so
+/// the scripts find tier `t` at `KEYS[11 + t]`.
+fn keys() -> Vec<String> {
+    let mut keys: Vec<String> = [
+        QUEUED,
+        PRIORITIES,
+        DEPTHS,
+        ENTRIES,
+        OWNERS,
+        LEASES,
+        ATTEMPTS,
+        ERRORS,
+        CREATED,
+        DEAD_LETTERS,
+    ]
+    .map(String::from)
+    .to_vec();
+    keys.extend((0..TIERS).map(stream));
+    keys
+}
+
+/// Shared by every queue script; `ARGV[1]` is the consumer group.
+const HELPERS: &str = r#"
+local group = ARGV[1]
+local max_tier = #KEYS - 11
+
+local function tier(priority)
+    local t = 0
+    priority = math.max(priority, 1)
+    while priority >= 2 and t < max_tier do
+        priority = math.floor(priority / 2)
+        t = t + 1
+    end
+    return t
+end
+
+local function add_entry(artist)
+    local t = tier(tonumber(redis.call("HGET", KEYS[2], artist) or "1"))
+    local id = redis.call("XADD", KEYS[11 + t], "*", "artist_id", artist)
+    redis.call("HSET", KEYS[4], artist, t .. "/" .. id)
+end
+
+-- Acknowledges and deletes the artist's stream entry and drops its lease
+local function remove_entry(artist)
+    local entry = redis.call("HGET", KEYS[4], artist)
+    if entry then
+        local t, id = string.match(entry, "^(%d+)/(.+)$")
+        local stream = KEYS[11 + tonumber(t)]
+        redis.call("XACK", stream, group, id)
+        redis.call("XDEL", stream, id)
+    end
+    redis.call("HDEL", KEYS[4], artist)
+    redis.call("HDEL", KEYS[5], artist)
+    redis.call("ZREM", KEYS[6], artist)
+end
+
+-- Puts the artist back at the end of its stream with no lease
+local function release(artist)
+    remove_entry(artist)
+    redis.call("SADD", KEYS[1], artist)
+    add_entry(artist)
+end
+
+-- Drops every trace of the artist from the queue
+local function forget(artist)
+    remove_entry(artist)
+    redis.call("SREM", KEYS[1], artist)
+    for _, key in ipairs({KEYS[2], KEYS[3], KEYS[7], KEYS[8], KEYS[9]}) do
+        redis.call("HDEL", key, artist)
+    end
+end
+
+local function holds_lease(artist, owner)
+    return redis.call("HGET", KEYS[5], artist) == owner
+end
+
+local function enqueue(artist, weight, depth, now)
+    if redis.call("SADD", KEYS[1], artist) == 1 then
+        redis.call("HSETNX", KEYS[9], artist, now)
+        redis.call("HSET", KEYS[2], artist, weight)
+        redis.call("HSET", KEYS[3], artist, depth)
+        add_entry(artist)
+        return
+    end
+    if redis.call("HEXISTS", KEYS[5], artist) == 1 then
+        return
+    end
+    if depth < tonumber(redis.call("HGET", KEYS[3], artist) or "0") then
+        redis.call("HSET", KEYS[3], artist, depth)
+    end
+    local previous = tonumber(redis.call("HGET", KEYS[2], artist) or "1")
+    local raised = redis.call("HINCRBY", KEYS[2], artist, weight)
+    -- Only a tier change moves the entry; within a tier, order is first come
+    if tier(raised) ~= tier(previous) then
+        remove_entry(artist)
+        add_entry(artist)
+    end
+end
+"#;
+
+/// ARGV: group, artist id, weight, depth, now
+const ENQUEUE_SCRIPT: &str = r#"
+enqueue(ARGV[2], tonumber(ARGV[3]), tonumber(ARGV[4]), ARGV[5])
+return 1
+"#;
+
+/// Reads the next entry, highest tier first, and leases it in the same step, so no
+/// entry is ever delivered without a lease the reaper can see.
+/// ARGV: group, consumer, lease expiry
+const DEQUEUE_SCRIPT: &str = r#"
+for t = max_tier, 0, -1 do
+    local stream = KEYS[11 + t]
+    local response = redis.call("XREADGROUP", "GROUP", group, ARGV[2], "COUNT", 1, "STREAMS", stream, ">")
+    if response then
+        local entry = response[1][2][1]
+        local fields = entry[2]
+        local artist
+        for i = 1, #fields, 2 do
+            if fields[i] == "artist_id" then
+                artist = fields[i + 1]
+            end
+        end
+        if artist then
+            redis.call("ZADD", KEYS[6], ARGV[3], artist)
+            redis.call("HSET", KEYS[5], artist, ARGV[2])
+            local priority = tonumber(redis.call("HGET", KEYS[2], artist) or "1")
+            local depth = tonumber(redis.call("HGET", KEYS[3], artist) or "0")
+            return {artist, priority, depth}
+        end
+        redis.call("XACK", stream, group, entry[1])
+        redis.call("XDEL", stream, entry[1])
+    end
+end
+return false
+"#;
+
+/// ARGV: group, artist id, owner, lease expiry
+const RENEW_SCRIPT: &str = r#"
+if not holds_lease(ARGV[2], ARGV[3]) or not redis.call("ZSCORE", KEYS[6], ARGV[2]) then
+    return 0
+end
+redis.call("ZADD", KEYS[6], ARGV[4], ARGV[2])
+return 1
+"#;
+
+/// ARGV: group, artist id, owner
+const ACK_SCRIPT: &str = r#"
+if not holds_lease(ARGV[2], ARGV[3]) then
+    return 0
+end
+forget(ARGV[2])
+return 1
+"#;
+
+/// ARGV: group, artist id, owner, error, max attempts, now
+const NACK_SCRIPT: &str = r#"
+local artist = ARGV[2]
+if not holds_lease(artist, ARGV[3]) then
+    return "lease_lost"
+end
+local attempts = redis.call("HINCRBY", KEYS[7], artist, 1)
+redis.call("HSET", KEYS[8], artist, ARGV[4])
+if attempts < tonumber(ARGV[5]) then
+    release(artist)
+    return "requeued"
+end
+local created = redis.call("HGET", KEYS[9], artist)
+local dead_letter = cjson.encode({
+    artist_id = artist,
+    attempts = attempts,
+    last_error = ARGV[4],
+    created_at = created and tonumber(created) or nil,
+    failed_at = tonumber(ARGV[6]),
+    depth = tonumber(redis.call("HGET", KEYS[3], artist) or "0"),
+})
+redis.call("HSET", KEYS[10], artist, dead_letter)
+forget(artist)
+return "dead_lettered"
+"#;
+
+/// ARGV: group, now
+const REAP_SCRIPT: &str = r#"
+local expired = redis.call("ZRANGEBYSCORE", KEYS[6], "-inf", ARGV[2])
+for _, artist in ipairs(expired) do
+    release(artist)
+end
+return #expired
+"#;
+
+/// ARGV: group, artist id, now
+const REQUEUE_SCRIPT: &str = r#"
+local dead_letter = redis.call("HGET", KEYS[10], ARGV[2])
+if not dead_letter then
+    return 0
+end
+redis.call("HDEL", KEYS[10], ARGV[2])
+enqueue(ARGV[2], 1, cjson.decode(dead_letter).depth or 0, ARGV[3])
+return 1
+"#;
+
+/// Redis Streams queue. Delivery goes through the `crawlers` consumer group of one
+/// stream per priority tier, read highest tier first; leases are tracked in a sorted
+/// set by expiry, since a stream entry's idle time cannot be extended without
+/// claiming it. Each operation is a single Lua script, so a consumer that dies
+/// mid-operation never leaves an entry delivered but unleased.
+pub struct RedisQueue {
+    client: RedisClient,
+    keys: Vec<String>,
+}
+
+impl RedisQueue {
+    pub async fn new(client: RedisClient) -> Result<Self, Box<dyn Error>> {
+        for tier in 0..TIERS {
+            match client
+                .xgroup_create::<(), _, _, _>(stream(tier), GROUP, "0", true)
+                .await
+            {
+                Ok(_) => {}
+                Err(e) if e.details().starts_with("BUSYGROUP") => {}
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Ok(RedisQueue {
+            client,
+            keys: keys(),
+        })
+    }
+
+    /// Runs `script` with the shared helpers, every queue key and the group ahead of
+    /// `args`.
+    async fn run<R: FromRedis>(
+        &self,
+        script: &str,
+        args: Vec<RedisValue>,
+    ) -> Result<R, Box<dyn Error>> {
+        let mut argv = vec![RedisValue::from(GROUP)];
+        argv.extend(args);
+        let result = self
+            .client
+            .eval(format!("{}{}", HELPERS, script), self.keys.clone(), argv)
+            .await?;
+        Ok(result)
+    }
+}
+
+#[async_trait(?Send)]
+impl TaskQueue for RedisQueue {
+    async fn enqueue(&self, discoveries: Vec<Discovery>) -> Result<(), Box<dyn Error>> {
+        for Discovery {
+            artist_id,
+            weight,
+            depth,
+        } in discoveries
+        {
+            self.run::<i64>(
+                ENQUEUE_SCRIPT,
+                vec![
+                    artist_id.into(),
+                    (weight as i64).into(),
+                    (depth as i64).into(),
+                    millis_from_now(Duration::ZERO).into(),
+                ],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn dequeue(
+        &self,
+        consumer: &str,
+        lease: Duration,
+    ) -> Result<Option<ArtistTask>, Box<dyn Error>> {
+        let lease_expires_at = millis_from_now(lease);
+        let leased: Option<(String, i32, i32)> = self
+            .run(
+                DEQUEUE_SCRIPT,
+                vec![consumer.into(), lease_expires_at.into()],
+            )
+            .await?;
+        Ok(leased.map(|(artist_id, priority, depth)| ArtistTask {
+            artist_id,
+            priority,
+            depth,
+            lease_owner: consumer.to_string(),
+            lease_expires_at,
+        }))
+    }
+
+    async fn renew(&self, task: &mut ArtistTask, lease: Duration) -> Result<bool, Box<dyn Error>> {
+        let lease_expires_at = millis_from_now(lease);
+        let renewed: i64 = self
+            .run(
+                RENEW_SCRIPT,
+                vec![
+                    task.artist_id.as_str().into(),
+                    task.lease_owner.as_str().into(),
+                    lease_expires_at.into(),
+                ],
+            )
+            .await?;
+        if renewed == 0 {
+            return Ok(false);
+        }
+        task.lease_expires_at = lease_expires_at;
+        Ok(true)
+    }
+
+    async fn ack(&self, task: &ArtistTask) -> Result<bool, Box<dyn Error>> {
+        let acked: i64 = self
+            .run(
+                ACK_SCRIPT,
+                vec![
+                    task.artist_id.as_str().into(),
+                    task.lease_owner.as_str().into(),
+                ],
+            )
+            .await?;
+        Ok(acked == 1)
+    }
+
+    async fn nack(&self, task: &ArtistTask, error: &str) -> Result<NackOutcome, Box<dyn Error>> {
+        let outcome: String = self
+            .run(
+                NACK_SCRIPT,
+                vec![
+                    task.artist_id.as_str().into(),
+                    task.lease_owner.as_str().into(),
+                    error.into(),
+                    (max_attempts() as i64).into(),
+                    millis_from_now(Duration::ZERO).into(),
+                ],
+            )
+            .await?;
+        match outcome.as_str() {
+            "requeued" => Ok(NackOutcome::Requeued),
+            "dead_lettered" => Ok(NackOutcome::DeadLettered),
+            _ => Ok(NackOutcome::LeaseLost),
+        }
+    }
+
+    async fn reap_expired(&self) -> Result<usize, Box<dyn Error>> {
+        let reaped: i64 = self
+            .run(REAP_SCRIPT, vec![millis_from_now(Duration::ZERO).into()])
+            .await?;
+        Ok(reaped as usize)
+    }
+
+    async fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, Box<dyn Error>> {
+        let dead_letters: HashMap<String, String> = self.client.hgetall(DEAD_LETTERS).await?;
+        let mut dead_letters = dead_letters
+            .values()
+            .map(|json| serde_json::from_str::<DeadLetter>(json))
+            .collect::<Result<Vec<_>, _>>()?;
+        dead_letters.sort_by(|a, b| a.artist_id.cmp(&b.artist_id));
+        dead_letters.truncate(limit);
+        Ok(dead_letters)
+    }
+
+    async fn requeue_dead_letters(
+        &self,
+        artist_ids: &[String],
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut requeued = Vec::new();
+        for artist_id in artist_ids {
+            let moved: i64 = self
+                .run(
+                    REQUEUE_SCRIPT,
+                    vec![
+                        artist_id.as_str().into(),
+                        millis_from_now(Duration::ZERO).into(),
+                    ],
+                )
+                .await?;
+            if moved == 1 {
+                requeued.push(artist_id.clone());
+            }
+        }
+        Ok(requeued)
+    }
+}
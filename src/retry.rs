@@ -0,0 +1,100 @@
+use std::{sync::OnceLock, time::Duration};
+
+use rand::Rng;
+use reqwest::StatusCode;
+
+use crate::env::env_or;
+
+static RETRY_POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+/// A status code, or a whole class like `5xx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatusMatch {
+    Class(u16),
+    Code(u16),
+}
+
+impl StatusMatch {
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        match value.strip_suffix("xx") {
+            Some(class) => class.parse().ok().map(StatusMatch::Class),
+            None => value.parse().ok().map(StatusMatch::Code),
+        }
+    }
+
+    fn matches(&self, status: StatusCode) -> bool {
+        match *self {
+            StatusMatch::Class(class) => status.as_u16() / 100 == class,
+            StatusMatch::Code(code) => status.as_u16() == code,
+        }
+    }
+}
+
+/// How Spotify API requests are retried, read from the environment.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// `SPOTIFY_MAX_ATTEMPTS`, tries per request including the first. 429s are waited
+    /// out by the rate limiter and do not count.
+    pub max_attempts: u32,
+    /// `SPOTIFY_MAX_RATE_LIMITED`, 429s waited out per request before giving up on it.
+    pub max_rate_limited: u32,
+    /// `SPOTIFY_RETRY_BASE_MS`, delay before the first retry, doubling after each.
+    pub base_delay: Duration,
+    /// `SPOTIFY_RETRY_MAX_MS`, cap on the delay between tries.
+    pub max_delay: Duration,
+    /// `SPOTIFY_RETRY_JITTER`, fraction of each delay that is randomized, so workers
+    /// that failed together do not retry together.
+    pub jitter: f64,
+    /// `SPOTIFY_RETRY_STATUSES`, comma-separated codes or classes, e.g. `5xx,408`.
+    retry_statuses: Vec<StatusMatch>,
+    /// `SPOTIFY_REQUEST_TIMEOUT_SECS`, per try.
+    pub request_timeout: Duration,
+    /// `ARTIST_DEADLINE_SECS`, how long crawling one artist may take in total.
+    pub artist_deadline: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        let retry_statuses = std::env::var("SPOTIFY_RETRY_STATUSES")
+            .unwrap_or("5xx,408".to_string())
+            .split(',')
+            .filter_map(StatusMatch::parse)
+            .collect();
+        RetryPolicy {
+            max_attempts: env_or("SPOTIFY_MAX_ATTEMPTS", 4).max(1),
+            max_rate_limited: env_or("SPOTIFY_MAX_RATE_LIMITED", 10),
+            base_delay: Duration::from_millis(env_or("SPOTIFY_RETRY_BASE_MS", 500)),
+            max_delay: Duration::from_millis(env_or("SPOTIFY_RETRY_MAX_MS", 30_000)),
+            jitter: env_or("SPOTIFY_RETRY_JITTER", 0.5_f64).clamp(0.0, 1.0),
+            retry_statuses,
+            request_timeout: Duration::from_secs(env_or("SPOTIFY_REQUEST_TIMEOUT_SECS", 10)),
+            artist_deadline: Duration::from_secs(env_or("ARTIST_DEADLINE_SECS", 900)),
+        }
+    }
+
+    pub fn retries_status(&self, status: StatusCode) -> bool {
+        self.retry_statuses
+            .iter()
+            .any(|retry_status| retry_status.matches(status))
+    }
+
+    /// Timeouts, failed connections and connections dropped mid-response.
+    pub fn retries_error(&self, error: &reqwest::Error) -> bool {
+        error.is_timeout() || error.is_connect() || error.is_request() || error.is_body()
+    }
+
+    /// Delay before try `attempt + 1`, after `attempt` failed tries.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let jittered = 1.0 - self.jitter * rand::thread_rng().gen::<f64>();
+        exponential.mul_f64(jittered)
+    }
+}
+
+pub fn retry_policy() -> &'static RetryPolicy {
+    RETRY_POLICY.get_or_init(RetryPolicy::from_env)
+}
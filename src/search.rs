@@ -0,0 +1,531 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    ops::Bound,
+    time::Instant,
+};
+
+use futures::StreamExt;
+use itertools::Itertools;
+use scylla::{query::Query, Session};
+use serde::Serialize;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::graph::CollaborationGraph;
+use crate::types::NormalizedArtist;
+
+const LOAD_PAGE_SIZE: i32 = 5000;
+const TOP_COLLABORATORS: usize = 3;
+pub const DEFAULT_SEARCH_LIMIT: usize = 10;
+/// Most indexed words one query word may match. Bounds the work of very short
+/// prefixes and loose typo matches; the first words in alphabetical order win.
+const MAX_WORD_MATCHES: usize = 1000;
+pub const MAX_SEARCH_LIMIT: usize = 50;
+
+/// How a query word matched a word of the artist name, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MatchKind {
+    Exact,
+    Prefix,
+    Typo,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub id: String,
+    pub name: String,
+    /// Number of distinct artists this one has collaborated with.
+    pub collaborators: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Collaborator {
+    pub id: String,
+    pub name: String,
+    /// Tracks shared with the candidate.
+    pub tracks: usize,
+}
+
+/// An artist carrying a given name, with enough context to tell it apart from
+/// namesakes.
+#[derive(Debug, Serialize)]
+pub struct NameCandidate {
+    pub id: String,
+    pub name: String,
+    pub collaborators: usize,
+    /// Collaboration tracks the artist appears on.
+    pub tracks: usize,
+    pub latest_release: Option<u16>,
+    pub top_collaborators: Vec<Collaborator>,
+}
+
+/// Lowercases, strips diacritics and splits on anything that is not alphanumeric,
+/// so "Beyoncé", "beyonce" and "BEYONCE!" all fold to the same words.
+fn fold(text: &str) -> Vec<String> {
+    let folded: String = text
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    folded.split_whitespace().map(String::from).collect()
+}
+
+/// Typos tolerated for a query word: none for short words, where a single edit
+/// already matches half the catalogue.
+fn max_typos(word: &str) -> usize {
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// The next Levenshtein row after appending `c` to the prefix `previous` describes:
+/// entry `i` is the edit distance between the first `i` query chars and the prefix.
+fn next_row(query: &[char], previous: &[usize], c: char) -> Vec<usize> {
+    let mut row = Vec::with_capacity(previous.len());
+    row.push(previous[0] + 1);
+    for (i, &q) in query.iter().enumerate() {
+        let substitution = previous[i] + usize::from(q != c);
+        row.push((previous[i + 1] + 1).min(row[i] + 1).min(substitution));
+    }
+    row
+}
+
+/// In-process artist name index backing `/artists/search`.
+///
+/// Names are folded into words, and every word maps to the artists whose name
+/// contains it. Words are kept in a `BTreeMap` so prefixes are range scans, and typo
+/// matching walks the sorted words as a trie, skipping every branch already too
+/// far from the query.
+#[derive(Debug, Default)]
+pub struct ArtistIndex {
+    ids: Vec<String>,
+    names: Vec<String>,
+    positions: HashMap<String, u32>,
+    words: BTreeMap<String, Vec<u32>>,
+    /// Folded full names, for exact name lookups.
+    full_names: HashMap<String, Vec<u32>>,
+}
+
+impl ArtistIndex {
+    pub async fn load(session: &Session) -> Result<Self, Box<dyn Error>> {
+        let before = Instant::now();
+        let query = Query::new("SELECT id, name FROM music.artists").with_page_size(LOAD_PAGE_SIZE);
+        let mut rows = session
+            .query_iter(query, ())
+            .await?
+            .into_typed::<(String, String)>();
+
+        let mut index = ArtistIndex::default();
+        while let Some(row) = rows.next().await {
+            let (id, name) = row?;
+            index.insert(&id, &name);
+        }
+
+        println!(
+            "Loaded artist search index with {} artists in {:?}",
+            index.artist_count(),
+            before.elapsed()
+        );
+        Ok(index)
+    }
+
+    pub fn add_artists(&mut self, artists: &[NormalizedArtist]) {
+        for artist in artists {
+            self.insert(&artist.id, &artist.name);
+        }
+    }
+
+    /// Adds an artist, or refreshes the name of one already indexed.
+    pub fn insert(&mut self, id: &str, name: &str) {
+        let position = match self.positions.get(id) {
+            Some(&position) => {
+                if self.names[position as usize] == name {
+                    return;
+                }
+                let words = fold(&self.names[position as usize]);
+                if let Some(artists) = self.full_names.get_mut(&words.join(" ")) {
+                    artists.retain(|&artist| artist != position);
+                }
+                for word in words {
+                    if let Some(artists) = self.words.get_mut(&word) {
+                        artists.retain(|&artist| artist != position);
+                        if artists.is_empty() {
+                            self.words.remove(&word);
+                        }
+                    }
+                }
+                self.names[position as usize] = name.to_string();
+                position
+            }
+            None => {
+                let position = self.ids.len() as u32;
+                self.ids.push(id.to_string());
+                self.names.push(name.to_string());
+                self.positions.insert(id.to_string(), position);
+                position
+            }
+        };
+        let words = fold(name);
+        self.full_names
+            .entry(words.join(" "))
+            .or_default()
+            .push(position);
+        for word in words {
+            let artists = self.words.entry(word).or_default();
+            if !artists.contains(&position) {
+                artists.push(position);
+            }
+        }
+    }
+
+    /// Indexed words starting with `prefix`, in order.
+    fn words_with_prefix<'a: 'p, 'p>(
+        &'a self,
+        prefix: &'p str,
+    ) -> impl Iterator<Item = (&'a String, &'a Vec<u32>)> + 'p {
+        self.words
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(move |(word, _)| word.starts_with(prefix))
+    }
+
+    /// Collects words within `typos` edits of `query`, or with a prefix that is.
+    /// `row` holds the edit distances of `prefix` to every prefix of `query`; a
+    /// branch is dropped as soon as no query prefix is within `typos` of it.
+    fn typo_words<'a>(
+        &'a self,
+        query: &[char],
+        typos: usize,
+        prefix: &mut String,
+        row: &[usize],
+        found: &mut Vec<&'a Vec<u32>>,
+    ) {
+        if found.len() >= MAX_WORD_MATCHES {
+            return;
+        }
+        if row[query.len()] <= typos {
+            let room = MAX_WORD_MATCHES - found.len();
+            found.extend(
+                self.words_with_prefix(prefix)
+                    .take(room)
+                    .map(|(_, artists)| artists),
+            );
+            return;
+        }
+        if row.iter().min().is_some_and(|&closest| closest > typos) {
+            return;
+        }
+        // Each child branch is found by seeking past the previous one
+        let mut start = prefix.clone();
+        while let Some((word, _)) = self
+            .words
+            .range::<str, _>((Bound::Included(start.as_str()), Bound::Unbounded))
+            .take_while(|(word, _)| word.starts_with(prefix.as_str()))
+            .find(|(word, _)| word.len() > prefix.len())
+        {
+            let Some(c) = word
+                .strip_prefix(prefix.as_str())
+                .and_then(|rest| rest.chars().next())
+            else {
+                break;
+            };
+            let child_row = next_row(query, row, c);
+            prefix.push(c);
+            self.typo_words(query, typos, prefix, &child_row, found);
+            prefix.pop();
+            let Some(next) = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32) else {
+                break;
+            };
+            start = format!("{}{}", prefix, next);
+        }
+    }
+
+    /// Best match of one query word against the indexed words, at most
+    /// `MAX_WORD_MATCHES` of them.
+    fn match_word(&self, query: &str) -> HashMap<u32, MatchKind> {
+        let mut matches: HashMap<u32, MatchKind> = HashMap::new();
+        let mut record = |artists: &[u32], kind: MatchKind| {
+            for &artist in artists {
+                matches
+                    .entry(artist)
+                    .and_modify(|best| *best = (*best).min(kind))
+                    .or_insert(kind);
+            }
+        };
+
+        for (word, artists) in self.words_with_prefix(query).take(MAX_WORD_MATCHES) {
+            let kind = if word == query {
+                MatchKind::Exact
+            } else {
+                MatchKind::Prefix
+            };
+            record(artists, kind);
+        }
+
+        let typos = max_typos(query);
+        if typos > 0 {
+            let query: Vec<char> = query.chars().collect();
+            let row: Vec<usize> = (0..=query.len()).collect();
+            let mut found = Vec::new();
+            self.typo_words(&query, typos, &mut String::new(), &row, &mut found);
+            for artists in found {
+                record(artists, MatchKind::Typo);
+            }
+        }
+        matches
+    }
+
+    /// Artists whose name matches every word of `query`, best matches first and,
+    /// among equally good matches, the most connected artists first.
+    pub fn search(&self, query: &str, limit: usize, graph: &CollaborationGraph) -> Vec<SearchHit> {
+        let words = fold(query);
+        let Some((first, rest)) = words.split_first() else {
+            return Vec::new();
+        };
+
+        let mut candidates = self.match_word(first);
+        for word in rest {
+            let matches = self.match_word(word);
+            candidates.retain(|artist, kind| match matches.get(artist) {
+                Some(&other) => {
+                    *kind = (*kind).max(other);
+                    true
+                }
+                None => false,
+            });
+        }
+
+        let folded_query = words.join(" ");
+        let mut hits: Vec<(bool, MatchKind, usize, u32)> = candidates
+            .into_iter()
+            .map(|(artist, kind)| {
+                let name = &self.names[artist as usize];
+                let exact_name = fold(name).join(" ") == folded_query;
+                let collaborators = graph
+                    .artist_index(&self.ids[artist as usize])
+                    .map_or(0, |index| graph.neighbors(index).len());
+                (!exact_name, kind, collaborators, artist)
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            (a.0, a.1)
+                .cmp(&(b.0, b.1))
+                .then_with(|| b.2.cmp(&a.2))
+                .then_with(|| {
+                    self.names[a.3 as usize]
+                        .len()
+                        .cmp(&self.names[b.3 as usize].len())
+                })
+        });
+
+        hits.into_iter()
+            .take(limit)
+            .map(|(_, _, collaborators, artist)| SearchHit {
+                id: self.ids[artist as usize].clone(),
+                name: self.names[artist as usize].clone(),
+                collaborators,
+            })
+            .collect()
+    }
+
+    /// Every artist whose folded name equals the folded `name`, with the most
+    /// prolific first.
+    pub fn candidates(&self, name: &str, graph: &CollaborationGraph) -> Vec<NameCandidate> {
+        let Some(artists) = self.full_names.get(&fold(name).join(" ")) else {
+            return Vec::new();
+        };
+        let mut candidates: Vec<NameCandidate> = artists
+            .iter()
+            .map(|&artist| self.candidate(artist, graph))
+            .collect();
+        candidates.sort_by(|a, b| {
+            b.tracks
+                .cmp(&a.tracks)
+                .then_with(|| b.collaborators.cmp(&a.collaborators))
+                .then_with(|| b.latest_release.cmp(&a.latest_release))
+        });
+        candidates
+    }
+
+    fn candidate(&self, artist: u32, graph: &CollaborationGraph) -> NameCandidate {
+        let id = &self.ids[artist as usize];
+        let mut candidate = NameCandidate {
+            id: id.clone(),
+            name: self.names[artist as usize].clone(),
+            collaborators: 0,
+            tracks: 0,
+            latest_release: None,
+            top_collaborators: Vec::new(),
+        };
+        let Some(index) = graph.artist_index(id) else {
+            return candidate;
+        };
+        let tracks = graph.artist_tracks(index);
+        candidate.collaborators = graph.neighbors(index).len();
+        candidate.tracks = tracks.len();
+        candidate.latest_release = tracks
+            .iter()
+            .filter_map(|&track| graph.track_year(track))
+            .max();
+        candidate.top_collaborators = graph
+            .neighbors(index)
+            .iter()
+            .sorted_by(|a, b| b.tracks.len().cmp(&a.tracks.len()))
+            .take(TOP_COLLABORATORS)
+            .map(|edge| {
+                let id = graph.artist_id(edge.neighbor);
+                Collaborator {
+                    id: id.to_string(),
+                    name: self
+                        .positions
+                        .get(id)
+                        .map(|&position| self.names[position as usize].clone())
+                        .unwrap_or_default(),
+                    tracks: edge.tracks.len(),
+                }
+            })
+            .collect();
+        candidate
+    }
+
+    /// Maps an artist id or name to an id. Ids and unknown values pass through
+    /// unchanged; a name shared by several artists is rejected with the candidates,
+    /// even when only one of them has collaborations, rather than guessed.
+    pub fn resolve(
+        &self,
+        value: &str,
+        graph: &CollaborationGraph,
+    ) -> Result<String, Vec<NameCandidate>> {
+        if graph.artist_index(value).is_some() || self.positions.contains_key(value) {
+            return Ok(value.to_string());
+        }
+        let candidates = self.candidates(value, graph);
+        match candidates.as_slice() {
+            [] => Ok(value.to_string()),
+            [only] => Ok(only.id.clone()),
+            _ => Err(candidates),
+        }
+    }
+
+    pub fn artist_count(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(artists: &[(&str, &str)]) -> ArtistIndex {
+        let mut index = ArtistIndex::default();
+        for (id, name) in artists {
+            index.insert(id, name);
+        }
+        index
+    }
+
+    /// Gives `artist` one collaboration track with each of `partners`.
+    fn collaborate(graph: &mut CollaborationGraph, artist: &str, partners: &[&str]) {
+        for partner in partners {
+            graph.add_track(
+                &format!("{}-{}", artist, partner),
+                &[artist.to_string(), partner.to_string()],
+                Some("2020-01-01"),
+            );
+        }
+    }
+
+    fn hits(index: &ArtistIndex, query: &str, graph: &CollaborationGraph) -> Vec<String> {
+        index
+            .search(query, MAX_SEARCH_LIMIT, graph)
+            .into_iter()
+            .map(|hit| hit.id)
+            .collect()
+    }
+
+    #[test]
+    fn fold_strips_case_diacritics_and_punctuation() {
+        assert_eq!(fold("Beyoncé"), ["beyonce"]);
+        assert_eq!(fold("BEYONCE!"), ["beyonce"]);
+        assert_eq!(fold("Sigur Rós"), ["sigur", "ros"]);
+        assert_eq!(fold("AC/DC"), ["ac", "dc"]);
+    }
+
+    #[test]
+    fn typo_tolerance_grows_with_word_length() {
+        assert_eq!(max_typos("abc"), 0);
+        assert_eq!(max_typos("abcd"), 1);
+        assert_eq!(max_typos("abcdefg"), 1);
+        assert_eq!(max_typos("abcdefgh"), 2);
+
+        let graph = CollaborationGraph::default();
+        let index = index(&[("drake", "Drake"), ("metallica", "Metallica")]);
+        assert_eq!(hits(&index, "drk", &graph), Vec::<String>::new());
+        assert_eq!(hits(&index, "drike", &graph), ["drake"]);
+        assert_eq!(hits(&index, "drikk", &graph), Vec::<String>::new());
+        assert_eq!(hits(&index, "metalika", &graph), ["metallica"]);
+        assert_eq!(hits(&index, "metalikx", &graph), Vec::<String>::new());
+        // A typo in a half-typed word
+        assert_eq!(hits(&index, "metalk", &graph), ["metallica"]);
+    }
+
+    #[test]
+    fn exact_names_then_exact_words_rank_above_prefixes() {
+        let mut graph = CollaborationGraph::default();
+        collaborate(&mut graph, "drakeo", &["x", "y", "z"]);
+        let index = index(&[
+            ("drakeo", "Drakeo the Ruler"),
+            ("bell", "Drake Bell"),
+            ("drake", "Drake"),
+        ]);
+        assert_eq!(hits(&index, "drake", &graph), ["drake", "bell", "drakeo"]);
+        assert_eq!(hits(&index, "dra", &graph), ["drakeo", "drake", "bell"]);
+    }
+
+    #[test]
+    fn renaming_replaces_the_old_words() {
+        let graph = CollaborationGraph::default();
+        let mut index = index(&[("1", "Old Name")]);
+        index.insert("1", "Brand New");
+        assert_eq!(hits(&index, "old", &graph), Vec::<String>::new());
+        assert_eq!(hits(&index, "brand new", &graph), ["1"]);
+        assert!(index.candidates("Old Name", &graph).is_empty());
+        assert_eq!(index.candidates("brand new", &graph).len(), 1);
+        assert_eq!(index.artist_count(), 1);
+    }
+
+    #[test]
+    fn resolve_rejects_shared_names_even_with_one_connected_namesake() {
+        let mut graph = CollaborationGraph::default();
+        collaborate(&mut graph, "smith-1", &["x"]);
+        let index = index(&[
+            ("smith-1", "John Smith"),
+            ("smith-2", "John Smith"),
+            ("doe", "Jane Doe"),
+        ]);
+        let candidates = index.resolve("John Smith", &graph).unwrap_err();
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(index.resolve("jane doe", &graph).unwrap(), "doe");
+        assert_eq!(index.resolve("smith-2", &graph).unwrap(), "smith-2");
+        assert_eq!(index.resolve("Nobody", &graph).unwrap(), "Nobody");
+    }
+
+    #[test]
+    fn candidates_list_namesakes_most_prolific_first() {
+        let mut graph = CollaborationGraph::default();
+        collaborate(&mut graph, "smith-2", &["x", "y"]);
+        collaborate(&mut graph, "smith-1", &["x"]);
+        let index = index(&[
+            ("smith-1", "John Smith"),
+            ("smith-2", "John Smith"),
+            ("other", "John Smithers"),
+        ]);
+        let candidates = index.candidates("JOHN SMITH", &graph);
+        let ids: Vec<&str> = candidates.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, ["smith-2", "smith-1"]);
+        assert_eq!(candidates[0].tracks, 2);
+        assert_eq!(candidates[0].latest_release, Some(2020));
+    }
+}
@@ -1,10 +1,153 @@
-use scylla::{batch::Batch, IntoTypedRows, Session};
+use std::{
+    cmp::Reverse,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
 
-// Updated struct to represent our task in ScyllaDB
-#[derive(Debug)]
+use futures::{future::join_all, stream, Future, StreamExt};
+use rand::Rng;
+use scylla::{frame::value::CqlTimestamp, transport::errors::QueryError, QueryResult, Session};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{micros_now, millis_from_now};
+use crate::db::{add_column, mark_migrated, migrated};
+use crate::env::env_or;
+
+/// Tasks are spread over this many `music.task_queue` partitions per status, so
+/// workers read small partitions instead of scanning the whole table.
+const BUCKETS: i32 = 16;
+/// Highest-priority pending tasks read per bucket when looking for one to claim.
+const CLAIM_CANDIDATES: i32 = 10;
+const CONCURRENT_ENQUEUES: usize = 32;
+
+const PENDING: &str = "pending";
+const PROCESSING: &str = "processing";
+
+const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+/// How long an index entry may disagree with its task before it is taken to be left
+/// over from a move that never finished, rather than part of one still in flight
+const STALE_ENTRY_GRACE: Duration = Duration::from_secs(60);
+/// Marks `index_unversioned_tasks` as done in `music.migrations`
+const INDEX_MIGRATION: &str = "index_unversioned_tasks";
+const UNLIMITED_DEPTH: &str = "unlimited";
+
+static NEXT_BUCKET: AtomicUsize = AtomicUsize::new(0);
+static MAX_DEPTH: OnceLock<Option<i32>> = OnceLock::new();
+
+/// A task claimed by a worker. The lease expires at `lease_expires_at` unless renewed,
+/// after which the reaper hands the artist to another worker.
+#[derive(Debug, Clone)]
 pub struct ArtistTask {
     pub artist_id: String,
-    pub status: String,
+    pub priority: i32,
+    /// Hops from the nearest seed artist
+    pub depth: i32,
+    pub lease_owner: String,
+    /// Milliseconds since the epoch
+    pub lease_expires_at: i64,
+}
+
+/// An artist found while crawling. `weight` is added to its priority every time it
+/// is discovered, so artists featured by many crawled artists rise to the front.
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    pub artist_id: String,
+    pub weight: i32,
+    /// Hops from the nearest seed artist, 0 for seeds. Rediscovering a pending artist
+    /// closer to a seed lowers its depth.
+    pub depth: i32,
+}
+
+/// What became of a task handed back as failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NackOutcome {
+    /// Back to pending for another attempt
+    Requeued,
+    /// Failed `TASK_MAX_ATTEMPTS` times and moved to the dead letters
+    DeadLettered,
+    /// The lease had passed to another worker, so nothing changed
+    LeaseLost,
+}
+
+/// A task that failed `TASK_MAX_ATTEMPTS` times and is no longer retried.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub artist_id: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    /// Depth the artist was queued at, restored when it is requeued
+    #[serde(default)]
+    pub depth: i32,
+    /// Milliseconds since the epoch
+    pub created_at: Option<i64>,
+    /// Milliseconds since the epoch
+    pub failed_at: Option<i64>,
+}
+
+/// FNV-1a, so an artist maps to the same bucket in every process.
+fn bucket_of(artist_id: &str) -> i32 {
+    let hash = artist_id.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    (hash % BUCKETS as u32) as i32
+}
+
+fn expires_in(duration: Duration) -> CqlTimestamp {
+    CqlTimestamp(millis_from_now(duration))
+}
+
+/// `TASK_MAX_ATTEMPTS`, failures after which a task is dead-lettered.
+pub fn max_attempts() -> i32 {
+    env_or("TASK_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS)
+}
+
+/// A `CRAWL_MAX_DEPTH` value: a depth of 0 or more, or `unlimited`. Surrounding
+/// whitespace is ignored; anything else is an error rather than an unbounded crawl.
+fn parse_max_depth(value: Option<&str>) -> Result<Option<i32>, String> {
+    let Some(value) = value.map(str::trim) else {
+        return Ok(None);
+    };
+    if value.is_empty() || value.eq_ignore_ascii_case(UNLIMITED_DEPTH) {
+        return Ok(None);
+    }
+    match value.parse::<i32>() {
+        Ok(depth) if depth >= 0 => Ok(Some(depth)),
+        _ => Err(format!(
+            "CRAWL_MAX_DEPTH must be a depth of 0 or more or `{}`, got {:?}",
+            UNLIMITED_DEPTH, value
+        )),
+    }
+}
+
+/// Reads and checks `CRAWL_MAX_DEPTH`. Called at startup so a malformed value stops
+/// the server instead of crawling without a limit.
+pub fn init_max_depth() -> Result<Option<i32>, String> {
+    let depth = read_max_depth()?;
+    let _ = MAX_DEPTH.set(depth);
+    Ok(depth)
+}
+
+fn read_max_depth() -> Result<Option<i32>, String> {
+    parse_max_depth(std::env::var("CRAWL_MAX_DEPTH").ok().as_deref())
+}
+
+/// `CRAWL_MAX_DEPTH`, hops from the seed artists beyond which discovered artists are
+/// stored but not crawled. Unset or `unlimited` crawls everything reachable.
+pub fn max_depth() -> Option<i32> {
+    *MAX_DEPTH.get_or_init(|| read_max_depth().expect("CRAWL_MAX_DEPTH is checked at startup"))
+}
+
+/// Whether a lightweight transaction was applied; Scylla returns `[applied]` first.
+pub fn applied(result: QueryResult) -> bool {
+    result
+        .first_row()
+        .ok()
+        .and_then(|row| row.columns.into_iter().next().flatten())
+        .and_then(|value| value.as_boolean())
+        .unwrap_or(false)
 }
 
 pub async fn setup_task_table(session: &Session) -> Result<(), Box<dyn std::error::Error>> {
@@ -18,58 +161,617 @@ pub async fn setup_task_table(session: &Session) -> Result<(), Box<dyn std::erro
             &[],
         )
         .await?;
+    add_column(session, "music.artist_tasks", "bucket", "int").await?;
+    add_column(session, "music.artist_tasks", "priority", "int").await?;
+    add_column(session, "music.artist_tasks", "depth", "int").await?;
+    add_column(session, "music.artist_tasks", "version", "bigint").await?;
+    add_column(session, "music.artist_tasks", "lease_owner", "text").await?;
+    add_column(
+        session,
+        "music.artist_tasks",
+        "lease_expires_at",
+        "timestamp",
+    )
+    .await?;
+    add_column(session, "music.artist_tasks", "attempts", "int").await?;
+    add_column(session, "music.artist_tasks", "last_error", "text").await?;
+    add_column(
+        session,
+        "music.artist_tasks",
+        "last_attempt_at",
+        "timestamp",
+    )
+    .await?;
+    session
+        .query(
+            "CREATE TABLE IF NOT EXISTS music.dead_letter_tasks (
+            artist_id text PRIMARY KEY,
+            attempts int,
+            last_error text,
+            created_at timestamp,
+            failed_at timestamp
+        )",
+            &[],
+        )
+        .await?;
+    add_column(session, "music.dead_letter_tasks", "depth", "int").await?;
+    session
+        .query(
+            "CREATE TABLE IF NOT EXISTS music.task_queue (
+            bucket int,
+            status text,
+            priority int,
+            artist_id text,
+            version bigint,
+            lease_owner text,
+            lease_expires_at timestamp,
+            PRIMARY KEY ((bucket, status), priority, artist_id, version)
+        ) WITH CLUSTERING ORDER BY (priority DESC, artist_id ASC, version ASC)",
+            &[],
+        )
+        .await?;
+    if !migrated(session, INDEX_MIGRATION).await? {
+        index_unversioned_tasks(session).await?;
+        mark_migrated(session, INDEX_MIGRATION).await?;
+    }
     Ok(())
 }
 
-pub async fn enqueue_tasks(
+/// Nanoseconds since the epoch with random low digits, so two workers moving a task
+/// at the same moment never pick the same version.
+fn next_version(previous: Option<i64>) -> i64 {
+    let version = micros_now() * 1000 + rand::thread_rng().gen_range(0..1000);
+    version.max(previous.map_or(0, |previous| previous + 1))
+}
+
+/// Current state of a queued artist. Tasks queued before depths were tracked count
+/// as seeds.
+struct TaskRow {
+    status: Option<String>,
+    priority: i32,
+    depth: i32,
+    /// Changes on every move between index entries, `None` for tasks not yet indexed
+    version: Option<i64>,
+}
+
+async fn task_row(
+    session: &Session,
+    artist_id: &str,
+) -> Result<Option<TaskRow>, Box<dyn std::error::Error>> {
+    let row = session
+        .query(
+            "SELECT status, priority, depth, version FROM music.artist_tasks WHERE artist_id = ?",
+            (artist_id,),
+        )
+        .await?
+        .maybe_first_row_typed::<(Option<String>, Option<i32>, Option<i32>, Option<i64>)>()?;
+    Ok(row.map(|(status, priority, depth, version)| TaskRow {
+        status,
+        priority: priority.unwrap_or(1),
+        depth: depth.unwrap_or(0),
+        version,
+    }))
+}
+
+/// A row of the `music.task_queue` index. Every move of a task writes a new entry
+/// under a new version, so no move overwrites or deletes an entry that belongs to
+/// another.
+struct Entry<'a> {
+    artist_id: &'a str,
+    status: &'a str,
+    priority: i32,
+    version: i64,
+}
+
+impl Entry<'_> {
+    async fn insert(
+        &self,
+        session: &Session,
+        lease: Option<(&str, CqlTimestamp)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        session
+            .query(
+                "INSERT INTO music.task_queue (bucket, status, priority, artist_id, version, lease_owner, lease_expires_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                (
+                    bucket_of(self.artist_id),
+                    self.status,
+                    self.priority,
+                    self.artist_id,
+                    self.version,
+                    lease.map(|(owner, _)| owner),
+                    lease.map(|(_, expires)| expires),
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+        session
+            .query(
+                "DELETE FROM music.task_queue WHERE bucket = ? AND status = ? AND priority = ? AND artist_id = ? AND version = ?",
+                (
+                    bucket_of(self.artist_id),
+                    self.status,
+                    self.priority,
+                    self.artist_id,
+                    self.version,
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes the entry if its task has moved past it (or is gone) and the entry is
+    /// too old to belong to a move still in flight.
+    async fn discard_if_stale(&self, session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+        let written_at = self.version / 1_000_000;
+        if written_at > millis_from_now(Duration::ZERO) - STALE_ENTRY_GRACE.as_millis() as i64 {
+            return Ok(());
+        }
+        let current = task_row(session, self.artist_id).await?;
+        if current.is_some_and(|row| row.version == Some(self.version)) {
+            return Ok(());
+        }
+        self.delete(session).await
+    }
+}
+
+/// Moves a task from index entry `from` to `to` by running `update`, a conditional
+/// update of `music.artist_tasks` that sets the task's version to `to.version`.
+///
+/// The new entry is written before the update and the old one deleted after, so the
+/// task's current state is always indexed. A crash in between leaves an extra entry
+/// behind, which `discard_if_stale` removes once a worker or the reaper reads it.
+/// Returns whether the update applied.
+async fn move_task(
     session: &Session,
-    artist_ids: Vec<String>,
+    from: Option<&Entry<'_>>,
+    to: &Entry<'_>,
+    lease: Option<(&str, CqlTimestamp)>,
+    update: impl Future<Output = Result<QueryResult, QueryError>>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    to.insert(session, lease).await?;
+    if !applied(update.await?) {
+        to.delete(session).await?;
+        return Ok(false);
+    }
+    if let Some(from) = from {
+        from.delete(session).await?;
+    }
+    Ok(true)
+}
+
+/// Indexes tasks written before tasks had versions, returning any that were being
+/// processed to pending since they have no lease that could expire.
+async fn index_unversioned_tasks(session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows = session
+        .query_iter(
+            "SELECT artist_id, priority, version FROM music.artist_tasks",
+            (),
+        )
+        .await?
+        .into_typed::<(String, Option<i32>, Option<i64>)>();
+    let mut migrated = 0;
+    while let Some(row) = rows.next().await {
+        let (artist_id, priority, version) = row?;
+        if version.is_some() {
+            continue;
+        }
+        let priority = priority.unwrap_or(1);
+        let to = Entry {
+            artist_id: &artist_id,
+            status: PENDING,
+            priority,
+            version: next_version(None),
+        };
+        let update = session.query(
+            "UPDATE music.artist_tasks SET status = ?, bucket = ?, priority = ?, version = ?, lease_owner = null, lease_expires_at = null WHERE artist_id = ? IF version = null",
+            (PENDING, bucket_of(&artist_id), priority, to.version, &artist_id),
+        );
+        if move_task(session, None, &to, None, update).await? {
+            migrated += 1;
+        }
+    }
+    if migrated > 0 {
+        println!("Indexed {} tasks in music.task_queue", migrated);
+    }
+    Ok(())
+}
+
+/// Adds the discovery weight to the priority of an artist that is still pending, and
+/// keeps the smaller of the two depths. Leased artists are left alone; a lost race
+/// with a worker or another discovery drops the update.
+async fn rediscover_task(
+    session: &Session,
+    discovery: &Discovery,
+    row: TaskRow,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut batch = Batch::default();
-    for _ in artist_ids.clone() {
-        batch.append_statement("INSERT INTO music.artist_tasks (artist_id, status) VALUES (?, ?)");
-    }
-    let statuses = artist_ids
-        .iter()
-        .map(|artist_id| (artist_id, "pending"))
-        .collect::<Vec<_>>();
-    session.batch(&mut batch, statuses).await?;
+    let artist_id = discovery.artist_id.as_str();
+    let (Some(PENDING), Some(version)) = (row.status.as_deref(), row.version) else {
+        return Ok(());
+    };
+    let from = Entry {
+        artist_id,
+        status: PENDING,
+        priority: row.priority,
+        version,
+    };
+    let to = Entry {
+        priority: row.priority.saturating_add(discovery.weight),
+        version: next_version(Some(version)),
+        ..from
+    };
+    let update = session.query(
+        "UPDATE music.artist_tasks SET priority = ?, depth = ?, version = ? WHERE artist_id = ? IF version = ?",
+        (to.priority, row.depth.min(discovery.depth), to.version, artist_id, version),
+    );
+    move_task(session, Some(&from), &to, None, update).await?;
     Ok(())
 }
 
+/// Adds newly discovered artists as pending, and raises the priority of artists
+/// already pending.
+pub async fn enqueue_tasks(
+    session: &Session,
+    discoveries: Vec<Discovery>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let results = stream::iter(discoveries)
+        .map(|discovery| async move {
+            let artist_id = discovery.artist_id.as_str();
+            if let Some(row) = task_row(session, artist_id).await? {
+                return rediscover_task(session, &discovery, row).await;
+            }
+            let to = Entry {
+                artist_id,
+                status: PENDING,
+                priority: discovery.weight,
+                version: next_version(None),
+            };
+            let insert = session.query(
+                "INSERT INTO music.artist_tasks (artist_id, status, bucket, priority, depth, version, created_at) VALUES (?, ?, ?, ?, ?, ?, currentTimestamp()) IF NOT EXISTS",
+                (artist_id, PENDING, bucket_of(artist_id), to.priority, discovery.depth, to.version),
+            );
+            if !move_task(session, None, &to, None, insert).await? {
+                // Another discovery of the same artist got there first
+                if let Some(row) = task_row(session, artist_id).await? {
+                    rediscover_task(session, &discovery, row).await?;
+                }
+            }
+            Ok::<_, Box<dyn std::error::Error>>(())
+        })
+        .buffer_unordered(CONCURRENT_ENQUEUES)
+        .collect::<Vec<_>>()
+        .await;
+    results.into_iter().collect()
+}
+
+/// Claims the highest-priority pending artist for `lease_owner` until the lease
+/// expires. The top of every bucket is read; ties are broken starting from a
+/// rotating bucket so workers do not all race for the same artist.
 pub async fn dequeue_task(
     session: &Session,
+    lease_owner: &str,
+    lease: Duration,
 ) -> Result<Option<ArtistTask>, Box<dyn std::error::Error>> {
-    let result = session.query(
-        "SELECT artist_id, status FROM music.artist_tasks WHERE status = ? LIMIT 1 ALLOW FILTERING",
-        ("pending",),
-    ).await?;
-
-    if let Some(rows) = result.rows {
-        for row in rows.into_typed::<(String, String)>() {
-            let (artist_id, status) = row?;
-            // Mark the task as processing
-            session
-                .query(
-                    "UPDATE music.artist_tasks SET status = ? WHERE artist_id = ? IF status = ?",
-                    ("processing", &artist_id, "pending"),
-                )
-                .await?;
-            return Ok(Some(ArtistTask { artist_id, status }));
+    let start = NEXT_BUCKET.fetch_add(1, Ordering::Relaxed) as i32;
+    let results = join_all((0..BUCKETS).map(|bucket| {
+        session.query(
+            "SELECT priority, artist_id, version FROM music.task_queue WHERE bucket = ? AND status = ? LIMIT ?",
+            (bucket, PENDING, CLAIM_CANDIDATES),
+        )
+    }))
+    .await;
+    let mut candidates = Vec::new();
+    for (bucket, result) in results.into_iter().enumerate() {
+        for row in result?.rows_typed_or_empty::<(i32, String, i64)>() {
+            let (priority, artist_id, version) = row?;
+            let rotation = (bucket as i32 - start).rem_euclid(BUCKETS);
+            candidates.push((Reverse(priority), rotation, artist_id, version));
+        }
+    }
+    candidates.sort_unstable();
+
+    for (Reverse(priority), _, artist_id, version) in candidates {
+        let from = Entry {
+            artist_id: &artist_id,
+            status: PENDING,
+            priority,
+            version,
+        };
+        let to = Entry {
+            status: PROCESSING,
+            version: next_version(Some(version)),
+            ..from
+        };
+        let lease_expires_at = expires_in(lease);
+        let claim = session.query(
+            "UPDATE music.artist_tasks SET status = ?, lease_owner = ?, lease_expires_at = ?, version = ? WHERE artist_id = ? IF version = ?",
+            (PROCESSING, lease_owner, lease_expires_at, to.version, &artist_id, version),
+        );
+        let lease_entry = Some((lease_owner, lease_expires_at));
+        // Another worker claimed it first, a new discovery moved it, or the entry is
+        // left over from a move that never finished
+        if !move_task(session, Some(&from), &to, lease_entry, claim).await? {
+            from.discard_if_stale(session).await?;
+            continue;
         }
+        let depth = match task_row(session, &artist_id).await? {
+            Some(row) => row.depth,
+            None => 0,
+        };
+        return Ok(Some(ArtistTask {
+            artist_id,
+            priority,
+            depth,
+            lease_owner: lease_owner.to_string(),
+            lease_expires_at: lease_expires_at.0,
+        }));
     }
     Ok(None)
 }
 
+/// Pushes the lease expiry forward. Returns false if the lease was lost to the reaper.
+pub async fn renew_lease(
+    session: &Session,
+    task: &mut ArtistTask,
+    lease: Duration,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let Some(TaskRow {
+        priority,
+        version: Some(version),
+        ..
+    }) = task_row(session, &task.artist_id).await?
+    else {
+        return Ok(false);
+    };
+    let lease_expires_at = expires_in(lease);
+    let result = session
+        .query(
+            "UPDATE music.artist_tasks SET lease_expires_at = ? WHERE artist_id = ? IF lease_owner = ? AND version = ?",
+            (lease_expires_at, &task.artist_id, &task.lease_owner, version),
+        )
+        .await?;
+    if !applied(result) {
+        return Ok(false);
+    }
+    task.lease_expires_at = lease_expires_at.0;
+    Entry {
+        artist_id: &task.artist_id,
+        status: PROCESSING,
+        priority,
+        version,
+    }
+    .insert(session, Some((&task.lease_owner, lease_expires_at)))
+    .await?;
+    Ok(true)
+}
+
+/// Returns tasks whose lease has expired to pending, for workers that crashed or
+/// stalled mid-artist, and clears out index entries left over from unfinished moves.
+/// Returns how many tasks were reclaimed.
+pub async fn reap_expired_leases(session: &Session) -> Result<usize, Box<dyn std::error::Error>> {
+    let now = expires_in(Duration::ZERO);
+    let mut reaped = 0;
+    for bucket in 0..BUCKETS {
+        let mut rows = session
+            .query_iter(
+                "SELECT priority, artist_id, version, lease_expires_at FROM music.task_queue WHERE bucket = ? AND status = ?",
+                (bucket, PROCESSING),
+            )
+            .await?
+            .into_typed::<(i32, String, i64, Option<CqlTimestamp>)>();
+        while let Some(row) = rows.next().await {
+            let (priority, artist_id, version, lease_expires_at) = row?;
+            if lease_expires_at.is_some_and(|expires| expires.0 > now.0) {
+                continue;
+            }
+            let from = Entry {
+                artist_id: &artist_id,
+                status: PROCESSING,
+                priority,
+                version,
+            };
+            let to = Entry {
+                status: PENDING,
+                version: next_version(Some(version)),
+                ..from
+            };
+            let release = session.query(
+                "UPDATE music.artist_tasks SET status = ?, lease_owner = null, lease_expires_at = null, version = ? WHERE artist_id = ? IF version = ? AND lease_expires_at < ?",
+                (PENDING, to.version, &artist_id, version, now),
+            );
+            if move_task(session, Some(&from), &to, None, release).await? {
+                reaped += 1;
+            } else {
+                // The lease was renewed or the task settled since the index was read
+                from.discard_if_stale(session).await?;
+            }
+        }
+    }
+    Ok(reaped)
+}
+
+/// Removes a processed task. Returns false, changing nothing, if the lease has
+/// passed to another worker.
 pub async fn complete_task(
     session: &Session,
-    artist_id: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    session
+    task: &ArtistTask,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let Some(TaskRow {
+        priority,
+        version: Some(version),
+        ..
+    }) = task_row(session, &task.artist_id).await?
+    else {
+        return Ok(false);
+    };
+    let result = session
         .query(
-            "DELETE FROM music.artist_tasks WHERE artist_id = ?",
+            "DELETE FROM music.artist_tasks WHERE artist_id = ? IF lease_owner = ? AND version = ?",
+            (&task.artist_id, &task.lease_owner, version),
+        )
+        .await?;
+    if !applied(result) {
+        return Ok(false);
+    }
+    Entry {
+        artist_id: &task.artist_id,
+        status: PROCESSING,
+        priority,
+        version,
+    }
+    .delete(session)
+    .await?;
+    Ok(true)
+}
+
+/// Records a failed attempt. The artist goes back to pending until it has failed
+/// `TASK_MAX_ATTEMPTS` times, then moves to `music.dead_letter_tasks`. Only the
+/// holder of the lease can do either.
+pub async fn fail_task(
+    session: &Session,
+    task: &ArtistTask,
+    error: &str,
+) -> Result<NackOutcome, Box<dyn std::error::Error>> {
+    let artist_id = task.artist_id.as_str();
+    let Some((attempts, created_at, depth, priority, Some(version))) = session
+        .query(
+            "SELECT attempts, created_at, depth, priority, version FROM music.artist_tasks WHERE artist_id = ?",
             (artist_id,),
         )
+        .await?
+        .maybe_first_row_typed::<(
+            Option<i32>,
+            Option<CqlTimestamp>,
+            Option<i32>,
+            Option<i32>,
+            Option<i64>,
+        )>()?
+    else {
+        return Ok(NackOutcome::LeaseLost);
+    };
+    let attempts = attempts.unwrap_or(0) + 1;
+    let now = expires_in(Duration::ZERO);
+
+    if attempts >= max_attempts() {
+        if !complete_task(session, task).await? {
+            return Ok(NackOutcome::LeaseLost);
+        }
+        session
+            .query(
+                "INSERT INTO music.dead_letter_tasks (artist_id, attempts, last_error, created_at, failed_at, depth) VALUES (?, ?, ?, ?, ?, ?)",
+                (artist_id, attempts, error, created_at, now, depth.unwrap_or(0)),
+            )
+            .await?;
+        return Ok(NackOutcome::DeadLettered);
+    }
+
+    let from = Entry {
+        artist_id,
+        status: PROCESSING,
+        priority: priority.unwrap_or(1),
+        version,
+    };
+    let to = Entry {
+        status: PENDING,
+        version: next_version(Some(version)),
+        ..from
+    };
+    let release = session.query(
+        "UPDATE music.artist_tasks SET status = ?, lease_owner = null, lease_expires_at = null, attempts = ?, last_error = ?, last_attempt_at = ?, version = ? WHERE artist_id = ? IF lease_owner = ? AND version = ?",
+        (PENDING, attempts, error, now, to.version, artist_id, &task.lease_owner, version),
+    );
+    if !move_task(session, Some(&from), &to, None, release).await? {
+        return Ok(NackOutcome::LeaseLost);
+    }
+    Ok(NackOutcome::Requeued)
+}
+
+pub async fn list_dead_letters(
+    session: &Session,
+    limit: usize,
+) -> Result<Vec<DeadLetter>, Box<dyn std::error::Error>> {
+    let result = session
+        .query(
+            "SELECT artist_id, attempts, last_error, created_at, failed_at, depth FROM music.dead_letter_tasks LIMIT ?",
+            (limit as i32,),
+        )
         .await?;
-    Ok(())
+    let mut dead_letters = Vec::new();
+    for row in result.rows_typed_or_empty::<(
+        String,
+        Option<i32>,
+        Option<String>,
+        Option<CqlTimestamp>,
+        Option<CqlTimestamp>,
+        Option<i32>,
+    )>() {
+        let (artist_id, attempts, last_error, created_at, failed_at, depth) = row?;
+        dead_letters.push(DeadLetter {
+            artist_id,
+            attempts: attempts.unwrap_or(0),
+            last_error,
+            created_at: created_at.map(|timestamp| timestamp.0),
+            failed_at: failed_at.map(|timestamp| timestamp.0),
+            depth: depth.unwrap_or(0),
+        });
+    }
+    Ok(dead_letters)
+}
+
+/// Moves dead-lettered artists back to pending with a fresh attempt count.
+/// Returns the ids that were actually dead-lettered.
+pub async fn requeue_dead_letters(
+    session: &Session,
+    artist_ids: &[String],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut requeued = Vec::new();
+    for artist_id in artist_ids {
+        let depth = session
+            .query(
+                "SELECT depth FROM music.dead_letter_tasks WHERE artist_id = ?",
+                (artist_id,),
+            )
+            .await?
+            .maybe_first_row_typed::<(Option<i32>,)>()?
+            .and_then(|(depth,)| depth)
+            .unwrap_or(0);
+        let result = session
+            .query(
+                "DELETE FROM music.dead_letter_tasks WHERE artist_id = ? IF EXISTS",
+                (artist_id,),
+            )
+            .await?;
+        if !applied(result) {
+            continue;
+        }
+        enqueue_tasks(
+            session,
+            vec![Discovery {
+                artist_id: artist_id.clone(),
+                weight: 1,
+                depth,
+            }],
+        )
+        .await?;
+        requeued.push(artist_id.clone());
+    }
+    Ok(requeued)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_depth_is_trimmed_and_malformed_values_are_rejected() {
+        assert_eq!(parse_max_depth(None), Ok(None));
+        assert_eq!(parse_max_depth(Some("unlimited")), Ok(None));
+        assert_eq!(parse_max_depth(Some(" Unlimited ")), Ok(None));
+        assert_eq!(parse_max_depth(Some("3 ")), Ok(Some(3)));
+        assert_eq!(parse_max_depth(Some("0")), Ok(Some(0)));
+        assert!(parse_max_depth(Some("three")).is_err());
+        assert!(parse_max_depth(Some("-1")).is_err());
+    }
 }
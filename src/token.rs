@@ -0,0 +1,170 @@
+use std::{error::Error, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::clock::millis_from_now;
+use crate::fetch::{get_api_key, get_client};
+
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+/// How long before expiry the background task swaps in a new token
+const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+/// Wait before trying again after a failed background refresh
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    pub value: String,
+    /// Milliseconds since the epoch
+    pub expires_at: i64,
+}
+
+impl AccessToken {
+    /// Time left before the token expires, zero once it has.
+    pub fn expires_in(&self) -> Duration {
+        let left = self.expires_at - millis_from_now(Duration::ZERO);
+        Duration::from_millis(left.max(0) as u64)
+    }
+}
+
+/// A source of Spotify API access tokens.
+#[async_trait(?Send)]
+pub trait TokenProvider: Send + Sync {
+    /// Fetches a new token, never a cached one.
+    async fn fetch_token(&self) -> Result<AccessToken, Box<dyn Error>>;
+}
+
+/// Holds the current access token and keeps it fresh: one background task replaces
+/// it shortly before it expires, and requests rejected with a 401 call `refresh_now`.
+/// Refreshes are single-flight, so a burst of 401s fetches one new token.
+pub struct TokenManager {
+    provider: Arc<dyn TokenProvider>,
+    token: RwLock<AccessToken>,
+    refreshing: Mutex<()>,
+}
+
+impl TokenManager {
+    /// Fetches the first token and spawns the background refresh task.
+    pub async fn start(provider: Arc<dyn TokenProvider>) -> Result<Arc<Self>, Box<dyn Error>> {
+        let token = provider.fetch_token().await?;
+        let manager = Arc::new(TokenManager {
+            provider,
+            token: RwLock::new(token),
+            refreshing: Mutex::new(()),
+        });
+        ntex::rt::spawn(keep_fresh(manager.clone()));
+        Ok(manager)
+    }
+
+    /// The token to send with the next request.
+    pub async fn current(&self) -> String {
+        self.token.read().await.value.clone()
+    }
+
+    /// Replaces `stale`, the token a request was rejected with, and returns the new
+    /// one. Callers that were rejected with a token another caller already replaced
+    /// get the replacement without fetching again.
+    pub async fn refresh_now(&self, stale: &str) -> Result<String, Box<dyn Error>> {
+        let _refreshing = self.refreshing.lock().await;
+        {
+            let token = self.token.read().await;
+            if token.value != stale {
+                return Ok(token.value.clone());
+            }
+        }
+        let token = self.provider.fetch_token().await?;
+        let value = token.value.clone();
+        *self.token.write().await = token;
+        Ok(value)
+    }
+}
+
+async fn keep_fresh(manager: Arc<TokenManager>) {
+    loop {
+        let (stale, expires_in) = {
+            let token = manager.token.read().await;
+            (token.value.clone(), token.expires_in())
+        };
+        tokio::time::sleep(expires_in.saturating_sub(REFRESH_MARGIN)).await;
+        if let Err(e) = manager.refresh_now(&stale).await {
+            eprintln!("Failed to refresh token: {:?}", e);
+            tokio::time::sleep(REFRESH_RETRY_DELAY).await;
+        }
+    }
+}
+
+/// Picks the provider from `SPOTIFY_TOKEN_PROVIDER` (`client_credentials` or
+/// `scraper`). Defaults to client credentials when `SPOTIFY_CLIENT_ID` is set.
+pub fn token_provider_from_env(
+    http_client: reqwest::Client,
+) -> Result<Arc<dyn TokenProvider>, Box<dyn Error>> {
+    let client_id = std::env::var("SPOTIFY_CLIENT_ID").ok();
+    let default = if client_id.is_some() {
+        "client_credentials"
+    } else {
+        "scraper"
+    };
+    let provider = std::env::var("SPOTIFY_TOKEN_PROVIDER").unwrap_or(default.to_string());
+    let token_provider: Arc<dyn TokenProvider> = match provider.as_str() {
+        "client_credentials" => Arc::new(ClientCredentials {
+            http_client,
+            client_id: client_id.ok_or("SPOTIFY_CLIENT_ID is not set")?,
+            client_secret: std::env::var("SPOTIFY_CLIENT_SECRET")
+                .map_err(|_| "SPOTIFY_CLIENT_SECRET is not set")?,
+        }),
+        "scraper" => Arc::new(WebPlayerScraper {
+            http_client: get_client(),
+        }),
+        other => return Err(format!("Unknown SPOTIFY_TOKEN_PROVIDER {}", other).into()),
+    };
+    println!("Using the {} token provider", provider);
+    Ok(token_provider)
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// Seconds
+    expires_in: u64,
+}
+
+/// OAuth client credentials flow with an app's client id and secret.
+pub struct ClientCredentials {
+    http_client: reqwest::Client,
+    client_id: String,
+    client_secret: String,
+}
+
+#[async_trait(?Send)]
+impl TokenProvider for ClientCredentials {
+    async fn fetch_token(&self) -> Result<AccessToken, Box<dyn Error>> {
+        let response: TokenResponse = self
+            .http_client
+            .post(TOKEN_URL)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("grant_type", "client_credentials")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(AccessToken {
+            value: response.access_token,
+            expires_at: millis_from_now(Duration::from_secs(response.expires_in)),
+        })
+    }
+}
+
+/// The anonymous web player token scraped from open.spotify.com. Needs no app
+/// credentials, but breaks whenever the page changes.
+pub struct WebPlayerScraper {
+    http_client: reqwest::Client,
+}
+
+#[async_trait(?Send)]
+impl TokenProvider for WebPlayerScraper {
+    async fn fetch_token(&self) -> Result<AccessToken, Box<dyn Error>> {
+        get_api_key(&self.http_client).await
+    }
+}
@@ -13,13 +13,22 @@ pub struct Album {
     pub id: String,
     pub name: String,
     pub release_date: String,
-    #[serde(rename = "type")]
     pub album_type: String,
     pub images: Vec<Image>,
     #[serde(default)]
+    pub total_tracks: usize,
+    /// Filled in by `fetch_albums_with_tracks`; Spotify nests them in a paging object.
+    #[serde(default)]
     pub tracks: Vec<Track>,
 }
 
+impl Album {
+    /// Spotify lists album images widest first.
+    pub fn cover_url(&self) -> Option<String> {
+        self.images.first().map(|image| image.url.clone())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Hash)]
 pub struct Track {
     pub id: String,
@@ -39,22 +48,29 @@ pub struct NormalizedTrack {
     pub name: String,
     pub preview_url: Option<String>,
     pub artists: Vec<String>,
+    pub release_date: Option<String>,
+    pub album_id: Option<String>,
+    pub album_name: Option<String>,
+    pub album_image: Option<String>,
 }
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Hash)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Hash, SerializeRow)]
 pub struct NormalizedArtist {
     pub id: String,
     pub name: String,
 }
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Hash)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Hash, SerializeRow)]
 pub struct NormalizedAlbum {
     pub id: String,
     pub name: String,
     pub release_date: String,
     #[serde(rename = "type")]
     pub album_type: String,
+    /// Image URLs, widest first.
+    pub images: Vec<String>,
+    /// Every track on the album, including ones without featured artists.
     pub tracks: Vec<String>,
 }
-fn normalize_albums(
+pub fn normalize_albums(
     albums: Vec<Album>,
 ) -> (
     Vec<NormalizedAlbum>,
@@ -68,6 +84,7 @@ fn normalize_albums(
 
     for album in albums {
         let mut album_tracks = Vec::new();
+        let album_image = album.cover_url();
 
         for track in album.tracks {
             let normalized_track = NormalizedTrack {
@@ -75,6 +92,10 @@ fn normalize_albums(
                 name: track.name,
                 preview_url: track.preview_url,
                 artists: track.artists.iter().map(|a| a.id.clone()).collect(),
+                release_date: Some(album.release_date.clone()),
+                album_id: Some(album.id.clone()),
+                album_name: Some(album.name.clone()),
+                album_image: album_image.clone(),
             };
             album_tracks.push(normalized_track.id.clone());
             normalized_tracks.push(normalized_track);
@@ -94,6 +115,7 @@ fn normalize_albums(
             name: album.name,
             release_date: album.release_date,
             album_type: album.album_type,
+            images: album.images.into_iter().map(|image| image.url).collect(),
             tracks: album_tracks,
         });
     }
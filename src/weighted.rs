@@ -0,0 +1,207 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::graph::{CollaborationGraph, Edge};
+use crate::path::{Budget, Exclusions, Search};
+
+const SECONDS_PER_YEAR: u64 = 31_556_952;
+
+/// Turns a collaboration into a distance: the more (and, with a half-life, the more
+/// recent) tracks two artists share, the shorter the hop between them.
+#[derive(Debug, Clone, Copy)]
+pub struct CollaborationWeights {
+    /// Years after which a shared track counts half as much. `None` ignores release dates.
+    pub recency_half_life: Option<f64>,
+    current_year: u16,
+}
+
+impl CollaborationWeights {
+    pub fn new(recency_half_life: Option<f64>) -> Self {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        CollaborationWeights {
+            recency_half_life: recency_half_life.filter(|half_life| *half_life > 0.0),
+            current_year: (1970 + seconds / SECONDS_PER_YEAR) as u16,
+        }
+    }
+
+    /// Inverse of the collaboration strength, counting only tracks the exclusions allow.
+    /// Tracks without a known release year are treated as one half-life old.
+    pub fn weight(&self, graph: &CollaborationGraph, edge: &Edge, exclusions: &Exclusions) -> f64 {
+        let strength: f64 = exclusions
+            .linking_tracks(graph, edge)
+            .map(|track| match self.recency_half_life {
+                None => 1.0,
+                Some(half_life) => {
+                    let age = graph
+                        .track_year(track)
+                        .map(|year| self.current_year.saturating_sub(year) as f64)
+                        .unwrap_or(half_life);
+                    0.5f64.powf(age / half_life)
+                }
+            })
+            .sum();
+        1.0 / strength
+    }
+
+    pub fn path_weight(
+        &self,
+        graph: &CollaborationGraph,
+        chain: &[u32],
+        exclusions: &Exclusions,
+    ) -> f64 {
+        chain
+            .windows(2)
+            .filter_map(|pair| graph.edge(pair[0], pair[1]))
+            .map(|edge| self.weight(graph, edge, exclusions))
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f64,
+    hops: usize,
+    artist: u32,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    // Reversed so the max-heap pops the closest artist first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance
+            .total_cmp(&self.distance)
+            .then_with(|| other.hops.cmp(&self.hops))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Dijkstra over collaboration weights, within `max_hops`.
+///
+/// A search state is an artist plus the hops taken to reach it, so an artist reached
+/// lightly over many hops is still expanded when it is later reached over fewer. A
+/// state is only kept if no state of the same artist is both lighter and as close.
+pub fn lightest_path(
+    graph: &CollaborationGraph,
+    source: u32,
+    target: u32,
+    max_hops: usize,
+    weights: &CollaborationWeights,
+    exclusions: &Exclusions,
+    budget: &mut Budget,
+) -> Search {
+    // (artist, hops) -> (artist before it, distance)
+    let mut best: HashMap<(u32, usize), (u32, f64)> = HashMap::from([((source, 0), (source, 0.0))]);
+    let mut heap = BinaryHeap::from([Candidate {
+        distance: 0.0,
+        hops: 0,
+        artist: source,
+    }]);
+    let dominated =
+        |best: &HashMap<(u32, usize), (u32, f64)>, artist: u32, hops: usize, distance: f64| {
+            (0..=hops).any(|fewer| {
+                best.get(&(artist, fewer))
+                    .is_some_and(|&(_, known)| known <= distance)
+            })
+        };
+
+    while let Some(Candidate {
+        distance,
+        hops,
+        artist,
+    }) = heap.pop()
+    {
+        if distance > best[&(artist, hops)].1 {
+            continue;
+        }
+        if artist == target {
+            let mut chain = vec![target];
+            let mut current = target;
+            for hop in (1..=hops).rev() {
+                current = best[&(current, hop)].0;
+                chain.push(current);
+            }
+            chain.reverse();
+            return Search::Found(chain);
+        }
+        if hops == max_hops {
+            continue;
+        }
+        if !budget.spend() {
+            return Search::GaveUp;
+        }
+        for edge in graph.neighbors(artist) {
+            if !exclusions.allows(graph, artist, edge) {
+                continue;
+            }
+            let next = distance + weights.weight(graph, edge, exclusions);
+            if !dominated(&best, edge.neighbor, hops + 1, next) {
+                best.insert((edge.neighbor, hops + 1), (artist, next));
+                heap.push(Candidate {
+                    distance: next,
+                    hops: hops + 1,
+                    artist: edge.neighbor,
+                });
+            }
+        }
+    }
+
+    Search::Exhausted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lightest_path_finds_light_paths_behind_heavier_hops_past_the_limit() {
+        // a-x and x-c share three tracks each, a-c and c-b one each
+        let mut graph = CollaborationGraph::default();
+        let links = [("a", "x", 3), ("x", "c", 3), ("a", "c", 1), ("c", "b", 1)];
+        for (a, b, tracks) in links {
+            for track in 0..tracks {
+                graph.add_track(
+                    &format!("{}{}{}", a, b, track),
+                    &[a.to_string(), b.to_string()],
+                    None,
+                );
+            }
+        }
+        let index = |id: &str| graph.artist_index(id).unwrap();
+        let weights = CollaborationWeights::new(None);
+        let search = |max_hops| {
+            lightest_path(
+                &graph,
+                index("a"),
+                index("b"),
+                max_hops,
+                &weights,
+                &Exclusions::default(),
+                &mut Budget::new(1000),
+            )
+        };
+        match search(3) {
+            Search::Found(chain) => {
+                assert_eq!(chain, [index("a"), index("x"), index("c"), index("b")])
+            }
+            _ => panic!("expected a path"),
+        }
+        match search(2) {
+            Search::Found(chain) => assert_eq!(chain, [index("a"), index("c"), index("b")]),
+            _ => panic!("expected a path within two hops"),
+        }
+        assert!(matches!(search(1), Search::Exhausted));
+    }
+}
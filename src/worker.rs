@@ -0,0 +1,193 @@
+use std::{error::Error, sync::Arc, time::Duration};
+
+use crate::env::env_or;
+use crate::task::{ArtistTask, NackOutcome};
+use crate::{process_single_artist, AppState};
+
+/// Background crawl settings, read from the environment.
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// `WORKER_COUNT`, 0 disables the background crawl.
+    pub workers: usize,
+    /// `WORKER_IDLE_BACKOFF_MS`, first wait after finding the queue empty.
+    pub idle_backoff: Duration,
+    /// `WORKER_MAX_IDLE_BACKOFF_MS`, the wait doubles up to this while the queue stays empty.
+    pub max_idle_backoff: Duration,
+    /// `TASK_LEASE_SECS`, how long a claimed artist stays invisible to other workers.
+    /// Workers renew it at a third of its length while processing.
+    pub lease: Duration,
+    /// `TASK_REAPER_INTERVAL_SECS`, how often expired leases are returned to pending.
+    pub reaper_interval: Duration,
+    /// `WORKER_RETRIES`, times a worker retries a failed artist itself, while still
+    /// holding the lease, before handing the failure back to the queue.
+    pub retries: u32,
+    /// `WORKER_RETRY_BACKOFF_MS`, wait before the first in-place retry, growing
+    /// linearly with each further one.
+    pub retry_backoff: Duration,
+    /// `WORKER_ID`, prefix of the lease owner names. Defaults to the host name.
+    pub worker_id: String,
+}
+
+impl WorkerConfig {
+    pub fn from_env() -> Self {
+        let host = std::env::var("HOSTNAME").unwrap_or("worker".to_string());
+        WorkerConfig {
+            workers: env_or("WORKER_COUNT", 4),
+            idle_backoff: Duration::from_millis(env_or("WORKER_IDLE_BACKOFF_MS", 500)),
+            max_idle_backoff: Duration::from_millis(env_or("WORKER_MAX_IDLE_BACKOFF_MS", 30_000)),
+            lease: Duration::from_secs(env_or("TASK_LEASE_SECS", 300)),
+            reaper_interval: Duration::from_secs(env_or("TASK_REAPER_INTERVAL_SECS", 60)),
+            retries: env_or("WORKER_RETRIES", 1),
+            retry_backoff: Duration::from_millis(env_or("WORKER_RETRY_BACKOFF_MS", 2000)),
+            worker_id: env_or("WORKER_ID", format!("{}-{}", host, std::process::id())),
+        }
+    }
+}
+
+/// Starts `config.workers` tasks draining the task queue on the current runtime,
+/// plus the reaper returning expired leases to pending.
+pub(crate) fn start_workers(state: Arc<AppState>, config: WorkerConfig) {
+    if config.workers == 0 {
+        return;
+    }
+    for worker in 0..config.workers {
+        ntex::rt::spawn(run_worker(state.clone(), worker, config.clone()));
+    }
+    ntex::rt::spawn(run_reaper(state, config.reaper_interval));
+    println!("Started {} crawl workers", config.workers);
+}
+
+async fn run_reaper(state: Arc<AppState>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match state.queue.reap_expired().await {
+            Ok(0) => {}
+            Ok(reaped) => println!("Returned {} expired task leases to pending", reaped),
+            Err(e) => eprintln!("Failed to reap expired task leases: {:?}", e),
+        }
+    }
+}
+
+async fn run_worker(state: Arc<AppState>, worker: usize, config: WorkerConfig) {
+    let lease_owner = format!("{}-{}", config.worker_id, worker);
+    let mut idle_backoff = config.idle_backoff;
+    loop {
+        match state.queue.dequeue(&lease_owner, config.lease).await {
+            Ok(Some(mut task)) => {
+                idle_backoff = config.idle_backoff;
+                let claimed = task.clone();
+                let processing = process_with_retries(&state, worker, &claimed, &config);
+                tokio::pin!(processing);
+                let mut renewal =
+                    tokio::time::interval((config.lease / 3).max(Duration::from_secs(1)));
+                renewal.tick().await;
+                let result = loop {
+                    tokio::select! {
+                        result = &mut processing => break Some(result),
+                        _ = renewal.tick() => {
+                            match state.queue.renew(&mut task, config.lease).await {
+                                Ok(true) => {}
+                                // Another worker may hold the artist now; stop crawling it
+                                Ok(false) => break None,
+                                Err(e) => eprintln!(
+                                    "Worker {} failed to renew its lease on artist {}: {:?}",
+                                    worker, task.artist_id, e
+                                ),
+                            }
+                        }
+                    }
+                };
+                let Some(result) = result else {
+                    eprintln!(
+                        "Worker {} lost its lease on artist {}, abandoning it",
+                        worker, task.artist_id
+                    );
+                    continue;
+                };
+                if let Err(e) = settle(&state, worker, &task, result).await {
+                    eprintln!(
+                        "Worker {} failed to update the task of artist {}: {:?}",
+                        worker, task.artist_id, e
+                    );
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("Worker {} failed to dequeue a task: {:?}", worker, e),
+        }
+        tokio::time::sleep(idle_backoff).await;
+        idle_backoff = (idle_backoff * 2).min(config.max_idle_backoff);
+    }
+}
+
+/// Processes the task's artist, retrying in place up to `config.retries` times.
+async fn process_with_retries(
+    state: &Arc<AppState>,
+    worker: usize,
+    task: &ArtistTask,
+    config: &WorkerConfig,
+) -> Result<bool, Box<dyn Error>> {
+    let mut retries = 0;
+    loop {
+        match process_single_artist(state, &task.artist_id, task.depth).await {
+            Err(e) if retries < config.retries => {
+                retries += 1;
+                let backoff = config.retry_backoff * retries;
+                eprintln!(
+                    "Worker {} failed on artist {} ({}), retrying in {:?}",
+                    worker, task.artist_id, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Acks or nacks the task depending on how processing went.
+///
+/// A task whose artist another instance was crawling is left leased, so the reaper
+/// hands it back once the lease expires.
+async fn settle(
+    state: &AppState,
+    worker: usize,
+    task: &ArtistTask,
+    result: Result<bool, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let artist_id = &task.artist_id;
+    let outcome = match result {
+        Ok(false) => {
+            println!(
+                "Worker {} skipped artist {}, another instance is crawling it; retrying after the lease expires",
+                worker, artist_id
+            );
+            return Ok(());
+        }
+        Ok(true) => {
+            println!("Worker {} processed artist {}", worker, artist_id);
+            if state.queue.ack(task).await? {
+                return Ok(());
+            }
+            NackOutcome::LeaseLost
+        }
+        Err(e) => {
+            eprintln!(
+                "Worker {} failed to process artist {}: {:?}",
+                worker, artist_id, e
+            );
+            state.queue.nack(task, &e.to_string()).await?
+        }
+    };
+    match outcome {
+        NackOutcome::Requeued => {}
+        NackOutcome::DeadLettered => {
+            eprintln!("Moved artist {} to the dead-letter queue", artist_id)
+        }
+        NackOutcome::LeaseLost => eprintln!(
+            "Worker {} finished artist {} after its lease passed to another worker",
+            worker, artist_id
+        ),
+    }
+    Ok(())
+}